@@ -0,0 +1,68 @@
+/* Builds a tree out of the points in a CSV file of x,y coordinates and answers queries
+typed on stdin. A line with a point, such as "1.5,-3", prints its nearest neighbor.
+A point followed by a number of neighbors, such as "1.5,-3 5", prints that many.
+
+cargo run --example csv_query examples/data/points.csv */
+
+use std::io::{self, BufRead, Write};
+use vptree::metrics::euclidean;
+use vptree::vptree::VPTree;
+
+fn parse_point(text: &str) -> Result<[f64; 2], String> {
+    let mut coordinates = text.split(',').map(|coordinate| {
+        coordinate
+            .trim()
+            .parse::<f64>()
+            .map_err(|error| format!("invalid coordinate {:?}: {}", coordinate, error))
+    });
+    match (coordinates.next(), coordinates.next(), coordinates.next()) {
+        (Some(x), Some(y), None) => Ok([x?, y?]),
+        _ => Err(format!("expected x,y but got {:?}", text)),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::args()
+        .nth(1)
+        .ok_or("usage: csv_query <points.csv>")?;
+    let mut tree = VPTree::new(euclidean);
+    for (number, line) in std::fs::read_to_string(&path)?.lines().enumerate() {
+        if !line.trim().is_empty() {
+            tree.insert(
+                parse_point(line).map_err(|error| format!("line {}: {}", number + 1, error))?,
+            );
+        }
+    }
+    tree.build();
+    println!("Loaded {} points from {}", tree.len(), path);
+
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        let needle = match words.next() {
+            Some(word) => word,
+            None => continue,
+        };
+        let query = parse_point(needle).and_then(|needle| match words.next() {
+            None => Ok((needle, 1)),
+            Some(k) => k
+                .parse::<usize>()
+                .map(|k| (needle, k))
+                .map_err(|error| format!("invalid number of neighbors {:?}: {}", k, error)),
+        });
+        match query {
+            Ok((needle, k)) => {
+                for (distance, point) in tree.find_k_nearest_neighbors(&needle, k) {
+                    println!("{},{} at {}", point[0], point[1], distance);
+                }
+            }
+            Err(error) => println!("{}", error),
+        }
+    }
+}
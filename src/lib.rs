@@ -1 +1,3 @@
-pub mod vptree;
\ No newline at end of file
+pub mod metrics;
+pub mod util;
+pub mod vptree;
@@ -0,0 +1,336 @@
+//! Ready-made distance functions that can be passed straight to `VPTree::new`.
+//!
+//! A vantage-point tree only finds the right neighbors if its distance function is a metric,
+//! in particular one that satisfies the triangle inequality. Everything here except
+//! `squared_euclidean` is one. For comparing vectors by direction, use `angular_distance`,
+//! not the cosine distance.
+
+use num_traits::float::TotalOrder;
+use num_traits::{Bounded, Float, Zero};
+use std::cmp::Ordering;
+use std::ops::{Add, Sub};
+
+/// Something that measures how far apart two items are, which is what a tree needs to be
+/// built. Every closure and function that takes two `&Item` implements it, so this only has
+/// to be implemented by hand for a distance function that is a type of its own, such as one
+/// that carries weights or can be serialized along with the tree.
+pub trait Metric<Item> {
+    type Distance;
+    fn distance(&self, a: &Item, b: &Item) -> Self::Distance;
+}
+
+impl<Item, Distance, F: Fn(&Item, &Item) -> Distance> Metric<Item> for F {
+    type Distance = Distance;
+    fn distance(&self, a: &Item, b: &Item) -> Distance {
+        self(a, b)
+    }
+}
+
+/// The straight-line distance between two points.
+pub fn euclidean<Point: AsRef<[F]>, F: Float>(a: &Point, b: &Point) -> F {
+    squared_euclidean(a, b).sqrt()
+}
+
+/// The square of the euclidean distance. This is *not* a metric, since it violates the
+/// triangle inequality, so a tree built with it will silently miss neighbors. It is only
+/// useful for comparing distances outside of a tree, where it saves the square root.
+pub fn squared_euclidean<Point: AsRef<[F]>, F: Float>(a: &Point, b: &Point) -> F {
+    a.as_ref()
+        .iter()
+        .zip(b.as_ref())
+        .fold(F::zero(), |sum, (&a, &b)| sum + (a - b) * (a - b))
+}
+
+/// The sum of the absolute differences between coordinates, also called taxicab distance.
+pub fn manhattan<Point: AsRef<[F]>, F: Float>(a: &Point, b: &Point) -> F {
+    a.as_ref()
+        .iter()
+        .zip(b.as_ref())
+        .fold(F::zero(), |sum, (&a, &b)| sum + (a - b).abs())
+}
+
+/// The largest absolute difference between coordinates.
+pub fn chebyshev<Point: AsRef<[F]>, F: Float>(a: &Point, b: &Point) -> F {
+    a.as_ref()
+        .iter()
+        .zip(b.as_ref())
+        .fold(F::zero(), |max, (&a, &b)| max.max((a - b).abs()))
+}
+
+/// The angle between two vectors, in radians, which doesn't depend on their lengths.
+/// This is the metric to use for comparing vectors by direction. The cosine distance,
+/// `1 - cosine similarity`, ranks neighbors the same way, but it violates the triangle
+/// inequality, so a tree built with it will silently miss neighbors.
+/// The angle is undefined for zero vectors, for which this returns NaN.
+pub fn angular_distance<Point: AsRef<[F]>, F: Float>(a: &Point, b: &Point) -> F {
+    let (dot, a_norm, b_norm) = a.as_ref().iter().zip(b.as_ref()).fold(
+        (F::zero(), F::zero(), F::zero()),
+        |(dot, a_norm, b_norm), (&a, &b)| (dot + a * b, a_norm + a * a, b_norm + b * b),
+    );
+    let cosine = dot / (a_norm * b_norm).sqrt();
+    /* Rounding errors can put the cosine of nearly parallel vectors just past 1.
+    Clamping it with max and min would also turn NaN into a number. */
+    if cosine > F::one() {
+        F::zero()
+    } else if cosine < -F::one() {
+        (-F::one()).acos()
+    } else {
+        cosine.acos()
+    }
+}
+
+/// The number of bits that differ between two byte strings of equal length.
+pub fn hamming<Bytes: AsRef<[u8]>>(a: &Bytes, b: &Bytes) -> u32 {
+    a.as_ref()
+        .iter()
+        .zip(b.as_ref())
+        .map(|(a, b)| (a ^ b).count_ones())
+        .sum()
+}
+
+/// A floating point distance that is ordered by `total_cmp` instead of `<`, which puts
+/// `-0.0` before `0.0` and NaN after infinity, so that no two distances are ever
+/// incomparable and building a tree and sorting the results of queries always order them
+/// the same way. Trees get distances like these from a metric wrapped in `TotallyOrdered`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TotalOrd<F>(pub F);
+
+impl<F: TotalOrder> PartialEq for TotalOrd<F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<F: TotalOrder> Eq for TotalOrd<F> {}
+
+impl<F: TotalOrder> PartialOrd for TotalOrd<F> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<F: TotalOrder> Ord for TotalOrd<F> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl<F: Bounded> Bounded for TotalOrd<F> {
+    fn min_value() -> Self {
+        TotalOrd(F::min_value())
+    }
+
+    fn max_value() -> Self {
+        TotalOrd(F::max_value())
+    }
+}
+
+impl<F: Add<Output = F>> Add for TotalOrd<F> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        TotalOrd(self.0 + other.0)
+    }
+}
+
+impl<F: Sub<Output = F>> Sub for TotalOrd<F> {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        TotalOrd(self.0 - other.0)
+    }
+}
+
+impl<F: Zero> Zero for TotalOrd<F> {
+    fn zero() -> Self {
+        TotalOrd(F::zero())
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+/// A metric with floating point distances, such as `euclidean`, whose distances are made
+/// `TotalOrd`, for a tree that orders them with `total_cmp`.
+/// `total_cmp` puts a NaN whose sign bit is set before every other distance, and that is
+/// the NaN `0.0 / 0.0` gives on x86, so every NaN is turned into a positive one, which
+/// is ordered after infinity, as if it was infinitely far away.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TotallyOrdered<M>(pub M);
+
+impl<Item, M> Metric<Item> for TotallyOrdered<M>
+where
+    M: Metric<Item>,
+    M::Distance: Float + TotalOrder,
+{
+    type Distance = TotalOrd<M::Distance>;
+    fn distance(&self, a: &Item, b: &Item) -> Self::Distance {
+        let distance = self.0.distance(a, b);
+        TotalOrd(if distance.is_nan() {
+            distance.abs()
+        } else {
+            distance
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vptree::VPTree;
+
+    #[test]
+    fn distances() {
+        let a = [1.0f32, 2.0, 3.0];
+        let b = [4.0f32, 6.0, 3.0];
+        assert_eq!(euclidean(&a, &b), 5.0);
+        assert_eq!(squared_euclidean(&a, &b), 25.0);
+        assert_eq!(manhattan(&a, &b), 7.0);
+        assert_eq!(chebyshev(&a, &b), 4.0);
+        assert_eq!(hamming(&[0b1010u8, 0xFF], &[0b0110u8, 0x0F]), 6);
+    }
+
+    #[test]
+    fn angles() {
+        let right_angle = angular_distance(&[3.0f64, 0.0], &[0.0, 0.5]);
+        assert!((right_angle - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+        assert_eq!(angular_distance(&[1.0f64, 1.0], &[2.0, 2.0]), 0.0);
+        assert!(
+            (angular_distance(&[1.0f64, 0.0], &[-1.0, 0.0]) - std::f64::consts::PI).abs() < 1e-12
+        );
+        assert!(angular_distance(&[0.0f64, 0.0], &[1.0, 0.0]).is_nan());
+
+        /* Pseudorandom vectors, from a linear congruential generator */
+        let mut state = 1u64;
+        let mut random = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 11) as f64 / (1u64 << 53) as f64 - 0.5
+        };
+        let vectors: Vec<[f64; 3]> = (0..300).map(|_| [random(), random(), random()]).collect();
+        let mut tree = VPTree::new(angular_distance);
+        tree.extend(vectors.clone());
+        for _ in 0..20 {
+            let needle = [random(), random(), random()];
+            let mut expected: Vec<f64> = vectors
+                .iter()
+                .map(|vector| angular_distance(&needle, vector))
+                .collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let actual: Vec<f64> = tree
+                .find_k_nearest_neighbors(&needle, 5)
+                .into_iter()
+                .map(|(distance, _)| distance)
+                .collect();
+            assert_eq!(actual, expected[..5].to_vec());
+        }
+    }
+    #[test]
+    fn as_distance_calculator() {
+        let points: Vec<Vec<f64>> = (0..50)
+            .map(|i| vec![(i % 7) as f64, (i / 7) as f64])
+            .collect();
+        let mut tree = VPTree::new(euclidean);
+        tree.extend(points);
+        assert_eq!(
+            tree.find_nearest_neighbor(&vec![2.2, 3.1]),
+            Some((euclidean(&vec![2.2, 3.1], &vec![2.0, 3.0]), vec![2.0, 3.0]))
+        );
+
+        let mut tree = VPTree::new(hamming);
+        tree.extend(vec![[0u8, 0], [0xFF, 0], [0x0F, 0xF0], [0, 0xFF]]);
+        assert_eq!(
+            tree.find_nearest_neighbor(&[0x0F, 0x70]),
+            Some((1, [0x0F, 0xF0]))
+        );
+    }
+    #[test]
+    fn metric_type() {
+        /* A weighted manhattan distance, which a closure could only borrow its weights for */
+        struct Weighted(Vec<f64>);
+        impl Metric<Vec<f64>> for Weighted {
+            type Distance = f64;
+            fn distance(&self, a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+                self.0
+                    .iter()
+                    .zip(a.iter().zip(b))
+                    .map(|(weight, (a, b))| weight * (a - b).abs())
+                    .sum()
+            }
+        }
+        let points: Vec<Vec<f64>> = (0..100)
+            .map(|i| vec![(i % 10) as f64, (i / 10) as f64])
+            .collect();
+        let mut tree = VPTree::build_from(points, Weighted(vec![1.0, 10.0]));
+        assert_eq!(tree.validate(), Ok(()));
+        let nearest = tree.find_k_nearest_neighbors(&vec![4.0, 4.25], 7);
+        assert_eq!(nearest[0], (2.5, vec![4.0, 4.0]));
+        let distances: Vec<f64> = nearest.into_iter().map(|(distance, _)| distance).collect();
+        assert_eq!(distances, vec![2.5, 3.5, 3.5, 4.5, 4.5, 5.5, 5.5]);
+
+        #[derive(Default)]
+        struct Absolute;
+        impl Metric<i32> for Absolute {
+            type Distance = i32;
+            fn distance(&self, a: &i32, b: &i32) -> i32 {
+                (a - b).abs()
+            }
+        }
+        let mut tree: VPTree<i32, i32, Absolute> = VPTree::default();
+        tree.extend(0..10);
+        assert_eq!(tree.find_nearest_neighbor(&12), Some((3, 9)));
+        let mut tree: VPTree<i32, i32, Absolute> = (0..100).map(|i| i * 2).collect();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.find_nearest_neighbor(&-5), Some((5, 0)));
+    }
+    #[test]
+    fn total_order() {
+        let points: Vec<[f64; 2]> = (0..100)
+            .map(|i| [(i % 10) as f64, (i / 10) as f64])
+            .collect();
+        let mut tree = VPTree::build_from(points.clone(), TotallyOrdered(euclidean));
+        assert_eq!(tree.validate(), Ok(()));
+        let mut plain = VPTree::build_from(points, euclidean);
+        for needle in [[2.2, 3.1], [-1.0, 14.0], [4.5, 4.5]].iter() {
+            let expected: Vec<f64> = plain
+                .find_k_nearest_neighbors(needle, 12)
+                .into_iter()
+                .map(|(distance, _)| distance)
+                .collect();
+            let actual: Vec<f64> = tree
+                .find_k_nearest_neighbors(needle, 12)
+                .into_iter()
+                .map(|(TotalOrd(distance), _)| distance)
+                .collect();
+            assert_eq!(actual, expected);
+        }
+
+        assert!(TotalOrd(-0.0f32) < TotalOrd(0.0));
+        assert!(TotalOrd(f64::INFINITY) < TotalOrd(f64::NAN));
+        assert_eq!(TotalOrd(f64::NAN), TotalOrd(f64::NAN));
+        /* A NaN with its sign bit set, which total_cmp would put first */
+        let nan = TotallyOrdered(|_: &f64, _: &f64| -f64::NAN).distance(&0.0, &0.0);
+        assert!(nan.0.is_nan() && nan > TotalOrd(f64::INFINITY));
+
+        /* Debug builds would panic on NaN distances if they weren't totally ordered */
+        let distance = |a: &f64, b: &f64| {
+            if a.is_nan() || b.is_nan() {
+                -f64::NAN
+            } else {
+                (a - b).abs()
+            }
+        };
+        let mut tree = VPTree::build_from(
+            vec![1.0, f64::NAN, 3.0, -2.0, 8.0, 5.0, f64::NAN, 0.5],
+            TotallyOrdered(distance),
+        );
+        let nearest: Vec<f64> = tree
+            .find_k_nearest_neighbors(&0.0, 6)
+            .into_iter()
+            .map(|(_, item)| item)
+            .collect();
+        assert_eq!(nearest, vec![0.5, 1.0, -2.0, 3.0, 5.0, 8.0]);
+        assert_eq!(tree.find_nearest_neighbor(&f64::NAN), None);
+    }
+}
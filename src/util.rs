@@ -0,0 +1,102 @@
+//! Reference implementations to check a tree's results against.
+
+use crate::vptree::compare;
+
+/// Finds the k items nearest to the needle by comparing it to every one of them, sorted by
+/// distance. Items at the same distance keep the order they have in `items`. This is what
+/// a tree's `find_k_nearest_neighbors` returns, only much slower.
+pub fn brute_force_k_nearest<Item, Distance, DistanceCalculator>(
+    items: &[Item],
+    needle: &Item,
+    k: usize,
+    distance_calculator: DistanceCalculator,
+) -> Vec<(Distance, Item)>
+where
+    Item: Clone,
+    Distance: PartialOrd,
+    DistanceCalculator: Fn(&Item, &Item) -> Distance,
+{
+    let mut neighbors: Vec<(Distance, Item)> = items
+        .iter()
+        .map(|item| (distance_calculator(needle, item), item.clone()))
+        .collect();
+    neighbors.sort_by(|a, b| compare(&a.0, &b.0));
+    neighbors.truncate(k);
+    neighbors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{chebyshev, euclidean, manhattan};
+    use crate::vptree::VPTree;
+    use proptest::prelude::*;
+
+    #[test]
+    fn brute_force() {
+        let items = [5, 1, 9, 3, 7];
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        assert_eq!(
+            brute_force_k_nearest(&items, &4, 3, distance),
+            vec![(1, 5), (1, 3), (3, 1)]
+        );
+        assert_eq!(brute_force_k_nearest(&items, &4, 10, distance).len(), 5);
+        assert_eq!(brute_force_k_nearest(&items, &4, 0, distance), Vec::new());
+    }
+
+    /* Only the distances are compared, since equally far items may come in either order */
+    fn distances<Item>(neighbors: Vec<(f64, Item)>) -> Vec<f64> {
+        neighbors
+            .into_iter()
+            .map(|(distance, _)| distance)
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn matches_brute_force(
+            points in prop::collection::vec([-100.0f64..100.0, -100.0f64..100.0], 0..300),
+            needles in prop::collection::vec([-150.0f64..150.0, -150.0f64..150.0], 1..10),
+            inserted in prop::collection::vec([-100.0f64..100.0, -100.0f64..100.0], 0..10),
+            k in 0usize..40,
+        ) {
+            for &metric in [euclidean, manhattan, chebyshev].iter() {
+                let mut tree = VPTree::build_from(points.clone(), metric);
+                let mut items = points.clone();
+                for point in inserted.iter() {
+                    tree.insert(*point);
+                    items.push(*point);
+                }
+                for needle in needles.iter() {
+                    let expected = distances(brute_force_k_nearest(&items, needle, k, metric));
+                    prop_assert_eq!(
+                        distances(tree.find_k_nearest_neighbors(needle, k)),
+                        expected.clone()
+                    );
+                    prop_assert_eq!(
+                        distances(tree.find_k_nearest_neighbors_best_first(needle, k)),
+                        expected.clone()
+                    );
+                    prop_assert_eq!(
+                        distances(tree.nearest_neighbors_iter(needle).take(k).collect()),
+                        expected.clone()
+                    );
+                    prop_assert_eq!(
+                        tree.find_nearest_neighbor(needle).map(|(distance, _)| distance),
+                        brute_force_k_nearest(&items, needle, 1, metric).first().map(|n| n.0)
+                    );
+                    /* Rounding errors can make items exactly at the radius go missing,
+                    so it's made a bit larger than the distance of the k-th neighbor */
+                    if let Some(&radius) = expected.last() {
+                        let radius = radius * (1.0 + 1e-9) + 1e-9;
+                        let within = distances(tree.find_neighbors_within_radius(needle, radius));
+                        prop_assert_eq!(&within[..expected.len()], &expected[..]);
+                        let all = brute_force_k_nearest(&items, needle, items.len(), metric);
+                        let all = distances(all);
+                        prop_assert_eq!(within.len(), all.iter().filter(|&&d| d <= radius).count());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,14 @@
-use num_traits::Bounded;
+use crate::metrics::Metric;
+use num_traits::{Bounded, NumCast, Zero};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 use std::cmp::{min, Ordering};
-use std::collections::VecDeque;
-use std::ops::Sub;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::iter::FromIterator;
+use std::mem::size_of;
+use std::ops::{Div, Mul, Sub};
 
 #[cfg(debug_assertions)]
 const FLAT_ARRAY_SIZE: usize = 3;
@@ -9,729 +16,5803 @@ const FLAT_ARRAY_SIZE: usize = 3;
 #[cfg(not(debug_assertions))]
 const FLAT_ARRAY_SIZE: usize = 50;
 
-struct Node<Item, Distance> {
-    vantage_point: Item,
-    radius: Distance,
+/* How many inserted items may wait outside of the tree before it has to be updated,
+unless set otherwise with with_max_pending */
+const MAX_PENDING: usize = FLAT_ARRAY_SIZE * 2;
+
+/* How many levels of nodes the Debug output of a tree shows */
+const DEBUG_DEPTH: u32 = 3;
+
+/* What a tree keeps all of its items in, vantage points and leaves alike. Everything is
+indexed by position, pushed, drained and retained, so a small-vector type with those methods
+can take its place to keep the items of tiny trees off the heap. */
+type ItemStorage<Item> = Vec<Item>;
+
+/* A node as the Debug output of a tree shows it */
+struct Node<'a, Item, Distance> {
+    vantage_point: &'a Item,
+    radius: &'a Distance,
+}
+
+impl<Item: fmt::Debug, Distance: fmt::Debug> fmt::Debug for Node<'_, Item, Distance> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("vantage_point", self.vantage_point)
+            .field("radius", self.radius)
+            .finish()
+    }
+}
+
+/// How the vantage point of every node is chosen out of the items below it. Vantage points
+/// that split their items into two well separated halves make queries that much faster,
+/// at the cost of building the tree a bit slower.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum VantagePointStrategy {
+    /// Takes whichever item ends up last, which costs nothing, but may make for lopsided
+    /// splits if the items were inserted sorted or clustered.
+    #[default]
+    Last,
+    /// Takes a random item. The same seed always builds the same tree out of the same items.
+    Random { seed: u64 },
+    /// Takes the item that was added to the tree first, so that the order items are added in
+    /// decides which of them become vantage points: the first item becomes the vantage point
+    /// of the root, the first of those on either side of it those of its children, and so on.
+    /// The same items added in the same order always build the same tree.
+    Earliest,
+    /// Compares `candidates` random items to `samples` random items each and takes the one
+    /// whose distances to them are the most spread out. Falls back to `Last` with fewer
+    /// than 4 samples.
+    MaxSpread {
+        candidates: usize,
+        samples: usize,
+        seed: u64,
+    },
+    /// Takes the vantage point of every node from the list, by the number of the item, the
+    /// same number the `_indexed` queries return. The first one is that of the root, and the
+    /// children of the node at position `i` in the list are at `2 * i + 1`, whose items are
+    /// within its radius, and `2 * i + 2`. Nodes past the end of the list, or whose item in it
+    /// ended up on the other side of an ancestor, fall back to `Last`.
+    PreselectedVantagePoints(Vec<usize>),
+}
+
+/// What `try_insert` did with an item.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The item was added to the few waiting outside of the tree, past its leaves, and
+    /// queries go on without rebuilding it.
+    AppendedToLeaf,
+    /// The item was put into the tree itself, and only the subtree at `node_index` was
+    /// rebuilt to make room for it. Nodes are numbered layer by layer from 0 at the root,
+    /// followed by the leaves, and when `node_index` is a leaf, nothing was rebuilt at all.
+    RebuiltSubtree { node_index: usize },
+    /// The tree has to be rebuilt before the next query, which the query or `build` will do,
+    /// because too many items are waiting outside of it or it hasn't been built since it
+    /// last changed.
+    TriggeredRebuild,
+}
+
+/* A small pseudorandom number generator (splitmix64), since building a tree needs only a
+handful of random numbers that don't have to be any good */
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64, index: usize) -> Self {
+        Random(seed ^ (index as u64).wrapping_mul(0xD1B5_4A32_D192_ED03))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /* A number in 0..bound, bound must not be 0 */
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/* A child of a node, either another node or a leaf, both by their own index. Telling them
+apart when a child is pushed to a stack means popping it doesn't have to. */
+#[derive(Clone, Copy)]
+enum Child {
+    Node(usize),
+    Leaf(usize),
+}
+
+/* An unexplored subtree in a best-first search. Ordered in reverse, so that a BinaryHeap
+pops the subtree whose boundary is closest to the needle first. */
+struct Unexplored<Distance> {
+    child: Child,
+    distance_to_boundary: Distance,
+}
+
+impl<Distance: PartialOrd> PartialEq for Unexplored<Distance> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Distance: PartialOrd> Eq for Unexplored<Distance> {}
+
+impl<Distance: PartialOrd> PartialOrd for Unexplored<Distance> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Distance: PartialOrd> Ord for Unexplored<Distance> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .distance_to_boundary
+            .partial_cmp(&self.distance_to_boundary)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/* A subtree or an item waiting to be looked at by a NearestNeighbors iterator, along with
+the least distance to the needle anything in it can have. Ordered in reverse, like
+Unexplored, and by rank afterwards, see VPTree::rank, so that items at the same distance
+come out in the same order as from the other queries. Subtrees come before items at the same
+distance, since they may still hold items that come before those. */
+struct Candidate<Distance> {
+    distance: Distance,
+    is_item: bool,
+    /* The rank of an item, or the index of a subtree */
+    rank: usize,
+    index: usize,
+}
+
+impl<Distance: PartialOrd> PartialEq for Candidate<Distance> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Distance: PartialOrd> Eq for Candidate<Distance> {}
+
+impl<Distance: PartialOrd> PartialOrd for Candidate<Distance> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Distance: PartialOrd> Ord for Candidate<Distance> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&other.distance, &self.distance)
+            .then(other.is_item.cmp(&self.is_item))
+            .then(other.rank.cmp(&self.rank))
+    }
+}
+
+/// Scratch space for queries. Passing the same context to the `_with` query methods
+/// saves them from allocating it anew on every call. The buffers grow as needed and the
+/// context can be reused for any number of queries, against any tree.
+pub struct QueryContext<Distance> {
+    unexplored: Vec<(Child, Distance)>,
+    candidates: BinaryHeap<Nearest<Distance>>,
+    nearest_neighbors: Vec<(Distance, usize)>,
+}
+
+impl<Distance: PartialOrd> QueryContext<Distance> {
+    pub fn new() -> Self {
+        Self {
+            unexplored: Vec::new(),
+            candidates: BinaryHeap::new(),
+            nearest_neighbors: Vec::new(),
+        }
+    }
+}
+
+impl<Distance: PartialOrd> Default for QueryContext<Distance> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An item found by a query, together with its distance to the needle. The query methods
+/// return `(Distance, Item)` tuples, which convert into and from this with `From`.
+///
+/// Neighbors are compared by distance alone, in the same order queries sort results in:
+/// distances that can't be compared, such as NaN, come after all the others. This makes
+/// them usable in a `BinaryHeap` or with `sort` even when the distance is a float.
+#[derive(Clone, Debug)]
+pub struct Neighbor<Item, Distance> {
+    pub distance: Distance,
+    pub item: Item,
+}
+
+impl<Item, Distance> From<(Distance, Item)> for Neighbor<Item, Distance> {
+    fn from((distance, item): (Distance, Item)) -> Self {
+        Self { distance, item }
+    }
+}
+
+impl<Item, Distance> From<Neighbor<Item, Distance>> for (Distance, Item) {
+    fn from(neighbor: Neighbor<Item, Distance>) -> Self {
+        (neighbor.distance, neighbor.item)
+    }
+}
+
+impl<Item, Distance: PartialOrd> PartialEq for Neighbor<Item, Distance> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Item, Distance: PartialOrd> Eq for Neighbor<Item, Distance> {}
+
+impl<Item, Distance: PartialOrd> PartialOrd for Neighbor<Item, Distance> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Item, Distance: PartialOrd> Ord for Neighbor<Item, Distance> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.distance, &other.distance)
+    }
+}
+
+/* Depth is the number of layers in a tree of len items, excluding the leaf layer,
+such that every leaf contains at most max_leaf_size items, but no fewer than about half as many.
+Root node has 2 children, those 2 children have 4 children in total and so on,
+for a total of 2^depth-1 nodes in a tree, if all layers are full, which is guaranteed
+in this implementation.
+The leaf layer is one additional layer below all the nodes, so its size is 2^depth.
+Leaves contain an array of items instead of just one because for short arrays linear search
+isn't less efficient than binary and not having to turn all items into nodes saves time. */
+fn depth(len: usize, max_leaf_size: usize) -> usize {
+    /* That is the smallest depth for which (len + 1) / (max_leaf_size + 1) <= 2^depth.
+    Rounding the ratio up doesn't change which power of two it fits in and makes it an
+    integer, len / (max_leaf_size + 1) + 1, which is 1 for trees that fit in a single
+    leaf, empty ones included, and 1 = 2^0. */
+    (len / max_leaf_size.saturating_add(1) + 1)
+        .next_power_of_two()
+        .trailing_zeros() as usize
+}
+
+/* The number of leaves in a tree of the given depth, 2^depth. For depths worked out by
+depth, this is at most twice len / (max_leaf_size + 1) + 1, so it can't overflow
+for any len, not even on 32 bit targets. */
+fn leaves_len(depth: usize) -> usize {
+    1usize
+        .checked_shl(depth as u32)
+        .expect("tree is too deep for its number of leaves to fit in a usize")
+}
+
+/* Where to split the items of a node, given the ideal sizes of its children.
+We want to give more items to the left side so that the leaves on the right side will have
+leaf_size long leaves. But we don't want to give the left side so many items that some of its
+leaves are more than leaf_size + 1 long. */
+fn split_point(len: usize, ideal_size_low: usize, ideal_size_high: usize) -> usize {
+    min(len - ideal_size_low, ideal_size_high)
+}
+
+/* Moves every item to where order says it belongs, order[position] being where the item that
+belongs at position currently is, by following the cycles of the permutation. The positions
+that are done are marked as pointing to themselves. */
+fn permute<T>(items: &mut [T], order: &mut [usize]) {
+    for start in 0..order.len() {
+        let mut position = start;
+        loop {
+            let next = order[position];
+            order[position] = position;
+            if next == start {
+                break;
+            }
+            items.swap(position, next);
+            position = next;
+        }
+    }
+}
+
+/// How much work a search took, see `find_nearest_neighbor_instrumented`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchStats {
+    /// How many times the distance calculator was called. This includes the nodes and
+    /// the items in the leaves, as well as inserted items that aren't part of the tree yet.
+    pub distance_calls: usize,
+    pub nodes_visited: usize,
+    pub leaves_visited: usize,
+}
+
+/* Gets told about everything a search looks at. The unit type ignores all of it,
+so that searches that aren't instrumented don't pay for the counting. */
+trait SearchCounter {
+    fn count_distance(&mut self) {}
+    fn count_node(&mut self) {}
+    fn count_leaf(&mut self) {}
+}
+
+impl SearchCounter for () {}
+
+impl SearchCounter for SearchStats {
+    fn count_distance(&mut self) {
+        self.distance_calls += 1;
+    }
+
+    fn count_node(&mut self) {
+        self.nodes_visited += 1;
+    }
+
+    fn count_leaf(&mut self) {
+        self.leaves_visited += 1;
+    }
+}
+
+/* Where a search keeps the subtrees it has yet to explore, each with the distance from the
+needle to its boundary. Most queries use a Vec, which they can keep between queries. */
+trait SubtreeStack<Distance> {
+    fn clear(&mut self);
+    fn push(&mut self, subtree: (Child, Distance));
+    fn pop(&mut self) -> Option<(Child, Distance)>;
+}
+
+impl<Distance> SubtreeStack<Distance> for Vec<(Child, Distance)> {
+    fn clear(&mut self) {
+        Vec::clear(self)
+    }
+
+    fn push(&mut self, subtree: (Child, Distance)) {
+        Vec::push(self, subtree)
+    }
+
+    fn pop(&mut self) -> Option<(Child, Distance)> {
+        Vec::pop(self)
+    }
+}
+
+/* A stack that never allocates. A search pushes one subtree for every node on its way down
+and pops it before going down from it, so it never holds more subtrees than the tree has
+layers of nodes, which is less than usize::BITS, see leaves_len. */
+struct ArrayStack<Distance> {
+    subtrees: [Option<(Child, Distance)>; usize::BITS as usize],
+    len: usize,
+}
+
+impl<Distance> ArrayStack<Distance> {
+    fn new() -> Self {
+        Self {
+            subtrees: std::array::from_fn(|_| None),
+            len: 0,
+        }
+    }
+}
+
+impl<Distance> SubtreeStack<Distance> for ArrayStack<Distance> {
+    fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    fn push(&mut self, subtree: (Child, Distance)) {
+        self.subtrees[self.len] = Some(subtree);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<(Child, Distance)> {
+        self.len = self.len.checked_sub(1)?;
+        self.subtrees[self.len].take()
+    }
+}
+
+/* Whether a distance can be compared at all, which NaN can't, not even to itself */
+fn is_comparable<Distance: PartialOrd>(distance: &Distance) -> bool {
+    distance.partial_cmp(distance).is_some()
+}
+
+/* The order distances are sorted in. Distances that can't be compared, such as NaN,
+are put after all the others, as if they were infinitely far away. */
+pub(crate) fn compare<Distance: PartialOrd>(a: &Distance, b: &Distance) -> Ordering {
+    a.partial_cmp(b)
+        .unwrap_or_else(|| is_comparable(b).cmp(&is_comparable(a)))
+}
+
+/* One of the k nearest items found so far by a search, with its distance, rank and index,
+ordered like compare_neighbors, so that a BinaryHeap of them keeps the farthest one on top */
+struct Nearest<Distance>(Distance, usize, usize);
+
+impl<Distance: PartialOrd> PartialEq for Nearest<Distance> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Distance: PartialOrd> Eq for Nearest<Distance> {}
+
+impl<Distance: PartialOrd> PartialOrd for Nearest<Distance> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Distance: PartialOrd> Ord for Nearest<Distance> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.0, &other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/* Adds an item to nearest_neighbors, which holds the k nearest items found so far, unless
+it's farther than all of them, and returns the new threshold for a k-nearest-neighbors
+search. Searches pass on items exactly at the threshold too, since one of them replaces the
+farthest neighbor if it's ranked before it, see VPTree::rank, which keeps the same items
+whatever order the search comes across them in. Items at least Distance::max_value() away
+never make it in. */
+fn consider_item<Distance: PartialOrd + Bounded + Clone>(
+    neighbor: Nearest<Distance>,
+    k: usize,
+    nearest_neighbors: &mut BinaryHeap<Nearest<Distance>>,
+) -> Distance {
+    if nearest_neighbors.len() < k {
+        if neighbor.0 < Distance::max_value() {
+            nearest_neighbors.push(neighbor);
+        }
+        if nearest_neighbors.len() == k {
+            /* Now that nearest_neigbors has reached its capacity of k,
+            we only want to add a new item if it's closer to needle
+            than an item in nearest_neighbors, so we set the threshold
+            to distance of farthest neighbor in nearest_neigbors */
+            nearest_neighbors.peek().unwrap().0.clone()
+        } else {
+            Distance::max_value()
+        }
+    } else {
+        /* The top of the heap is the farthest neighbor, which a nearer one replaces */
+        let mut farthest = nearest_neighbors.peek_mut().unwrap();
+        if neighbor < *farthest {
+            *farthest = neighbor;
+        }
+        drop(farthest);
+        nearest_neighbors.peek().unwrap().0.clone()
+    }
 }
 
+/* Moves the neighbors out of candidates into nearest_neighbors, sorted, keeping the memory
+of both */
+fn sort_neighbors<Distance: PartialOrd>(
+    candidates: &mut BinaryHeap<Nearest<Distance>>,
+    nearest_neighbors: &mut Vec<(Distance, usize)>,
+) {
+    let mut sorted = std::mem::take(candidates).into_sorted_vec();
+    nearest_neighbors.extend(
+        sorted
+            .drain(..)
+            .map(|Nearest(distance, _, index)| (distance, index)),
+    );
+    *candidates = BinaryHeap::from(sorted);
+}
+
+/// A vantage-point tree of `Item`s, with distances between them measured by `DistanceCalculator`.
+///
+/// The distance calculator has to be a metric and every distance it returns has to be
+/// comparable, which for floating point distances means it must never return NaN.
+/// Debug builds check for this and panic on NaN. Release builds don't: items at a NaN
+/// distance are never returned by any query, and one turning up while building the tree
+/// can cause queries to miss some of the other items.
+///
+/// Nearest neighbor queries start out looking for items closer than `Distance::max_value()`,
+/// so they never return items that are at least that far from the needle, such as ones at
+/// an infinite distance. This takes some care with `half::f16` distances, which round to
+/// infinity a little past 65504: the distance calculator should scale them to fit.
+///
+/// Queries that return several items sort them by distance. Items at the same distance are
+/// sorted by the order they were added to the tree in, and when there are more of them than
+/// a query asks for, the ones added first are returned, however the tree is shaped. Trees
+/// made with `new_deterministic` sort them by where they are stored instead, which doesn't
+/// depend on the order the items were added in.
+///
+/// With floating point distances, the triangle inequality can be off by a rounding error,
+/// so radius searches may miss items that are exactly `threshold` away from the needle.
+///
+/// Every node splits the items below it in half, even when many of them are exactly as far
+/// from its vantage point, so the depth of the tree only depends on how many items it has.
+/// Such ties only make queries slower, since they have to search both sides of the node.
+/// A vantage point that all the other items are equally far from, such as the center of a
+/// circle they lie on, is replaced by one of them.
+///
+/// Every item is stored once, vantage points included, along with the number of items
+/// added before it. Only the queries that return items need them to be `Clone`, since they
+/// return copies of them.
+///
+/// A tree can be cloned if its items, distances and distance calculator can, as is the case
+/// for function pointers and closures that capture nothing or only `Clone` values.
+#[derive(Clone)]
 pub struct VPTree<Item, Distance, DistanceCalculator>
 where
-    Item: Clone,
     Distance: PartialOrd + Bounded + Sub<Output = Distance>,
-    DistanceCalculator: Fn(&Item, &Item) -> Distance,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
 {
     distance_calculator: DistanceCalculator,
-    nodes: Vec<Node<Item, Distance>>,
-    leaves: Vec<Item>,
+    /* The vantage points of the nodes, followed by the leaves. The radius of the node at
+    index is radii[index], so nodes don't keep copies of their vantage points and the index
+    of an item is simply its position. */
+    items: ItemStorage<Item>,
+    radii: Vec<Distance>,
+    /* The number of items in the subtree of every node, its vantage point included,
+    subtree_lens[index] for the node at index */
+    subtree_lens: Vec<usize>,
+    /* The number of every item in the order items were added to the tree, ids[position]
+    for the item at position, and the number the next item added will get */
+    ids: Vec<usize>,
+    next_id: usize,
     leaf_size: usize,
     decrementation_point: usize,
     depth: usize,
     is_updated: bool,
+    vantage_point_strategy: VantagePointStrategy,
+    max_pending: usize,
+    /* The most items a leaf is built with, FLAT_ARRAY_SIZE unless set otherwise with
+    with_leaf_size, and leaf_size the fewest */
+    max_leaf_size: usize,
+    /* Set by new_deterministic, items are sorted with it before every build */
+    item_order: Option<fn(&Item, &Item) -> Ordering>,
 }
 
 impl<Item, Distance, DistanceCalculator> VPTree<Item, Distance, DistanceCalculator>
 where
-    Item: Clone,
-    Distance: Copy + PartialOrd + Bounded + Sub<Output = Distance>,
-    DistanceCalculator: Fn(&Item, &Item) -> Distance,
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
 {
     pub fn new(distance_calculator: DistanceCalculator) -> Self {
         Self {
             distance_calculator,
-            nodes: Vec::new(),
-            leaves: Vec::new(),
+            items: ItemStorage::new(),
+            radii: Vec::new(),
+            subtree_lens: Vec::new(),
+            ids: Vec::new(),
+            next_id: 0,
             leaf_size: 0,
             decrementation_point: 0,
             depth: 0,
             is_updated: false,
+            vantage_point_strategy: VantagePointStrategy::default(),
+            max_pending: MAX_PENDING,
+            max_leaf_size: FLAT_ARRAY_SIZE,
+            item_order: None,
+        }
+    }
+
+    /// Creates an empty tree that is always built the same way out of the same items, no
+    /// matter what order they were added in, so that queries return the same results in the
+    /// same order too. By default, the shape of the tree depends on the order of the items,
+    /// which decides the vantage points and how items equally far from one end up.
+    ///
+    /// The items are sorted before every build to achieve this, which adds n log n
+    /// comparisons of items to every build: for a million points compared as pairs of
+    /// integers, building took about a third longer. Queries are just as fast. The `Earliest`
+    /// vantage point strategy still picks vantage points by the order items were added in, so
+    /// it gives up this guarantee.
+    pub fn new_deterministic(distance_calculator: DistanceCalculator) -> Self
+    where
+        Item: Ord,
+    {
+        Self {
+            item_order: Some(Item::cmp),
+            ..Self::new(distance_calculator)
         }
     }
 
+    /// Sets how vantage points are chosen when the tree is built, see `VantagePointStrategy`.
+    /// Takes effect the next time the tree is built.
+    pub fn with_vantage_point_strategy(mut self, strategy: VantagePointStrategy) -> Self {
+        self.vantage_point_strategy = strategy;
+        self.is_updated = false;
+        self
+    }
+
+    /// Sets how many inserted items may wait outside of the tree, compared to needles one by
+    /// one, before the next query rebuilds it. A larger limit means fewer rebuilds for trees
+    /// that are inserted into often, at the cost of slower queries in between them. Zero
+    /// rebuilds the tree after every insert.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.max_pending = max_pending;
+        self.check_pending();
+        self
+    }
+
+    /// Sets the most items a leaf of the tree holds, by default 50, or 3 in debug builds.
+    /// Leaves are searched by comparing the needle to every item in them, so larger leaves
+    /// mean fewer nodes to visit but more items to compare, which pays off for distances
+    /// that are quick to calculate. Leaves always hold at least about half as many items,
+    /// unless the whole tree holds fewer. Takes effect the next time the tree is built.
+    /// Panics if `leaf_size` is less than 3.
+    pub fn with_leaf_size(mut self, leaf_size: usize) -> Self {
+        self.set_leaf_size(leaf_size);
+        self
+    }
+
+    /// Same as `with_leaf_size`, but for a tree that is already in use, which it rebuilds
+    /// right away, so that trying out different leaf sizes on the same items takes one
+    /// line per leaf size, along with `leaf_occupancy` to see how full the leaves ended up.
+    /// Rebuilding takes as long as `update`.
+    pub fn rebuild_with_leaf_size(&mut self, leaf_size: usize) {
+        self.set_leaf_size(leaf_size);
+        self.update();
+    }
+
+    fn set_leaf_size(&mut self, leaf_size: usize) {
+        /* With fewer, the smallest trees of some depths would have more leaves than items
+        to put in them, which split_subtree doesn't expect */
+        assert!(
+            leaf_size >= 3,
+            "leaves have to be able to hold at least 3 items"
+        );
+        self.max_leaf_size = leaf_size;
+        self.is_updated = false;
+    }
+
+    /// Creates an empty tree with room for `capacity` items, so that adding them and
+    /// building the tree doesn't have to allocate memory again.
+    pub fn with_capacity(distance_calculator: DistanceCalculator, capacity: usize) -> Self {
+        let mut tree = Self::new(distance_calculator);
+        tree.items.reserve(capacity);
+        tree.radii
+            .reserve(leaves_len(depth(capacity, tree.max_leaf_size)) - 1);
+        tree.subtree_lens
+            .reserve(leaves_len(depth(capacity, tree.max_leaf_size)) - 1);
+        tree.ids.reserve(capacity);
+        tree
+    }
+
+    /// Creates a tree out of `items` and builds it right away.
+    pub fn build_from<I: IntoIterator<Item = Item>>(
+        items: I,
+        distance_calculator: DistanceCalculator,
+    ) -> Self {
+        let mut tree = Self::new(distance_calculator);
+        tree.extend(items);
+        tree.update();
+        tree
+    }
+
+    /// Same as `build_from`, but builds the tree with `strategy` instead of the default one,
+    /// see `with_vantage_point_strategy`.
+    pub fn new_with_strategy<I: IntoIterator<Item = Item>>(
+        items: I,
+        distance_calculator: DistanceCalculator,
+        strategy: VantagePointStrategy,
+    ) -> Self {
+        let mut tree = Self::new(distance_calculator).with_vantage_point_strategy(strategy);
+        tree.extend(items);
+        tree.update();
+        tree
+    }
+
+    /// Same as `build_from`, for items that are known to be all different and spread out.
+    /// The tree is built exactly the same way for now, but building it may skip work meant
+    /// for many equal items in the future, so items that don't meet this may make for a
+    /// slower tree. Queries still return the right results either way.
+    pub fn build_from_unique<I: IntoIterator<Item = Item>>(
+        items: I,
+        distance_calculator: DistanceCalculator,
+    ) -> Self {
+        Self::build_from(items, distance_calculator)
+    }
+
+    /// Same as `build_from`, but leaves out items equal to one that came before them.
+    /// Returns the tree along with how many items were left out.
+    pub fn new_dedup<I: IntoIterator<Item = Item>>(
+        items: I,
+        distance_calculator: DistanceCalculator,
+    ) -> (Self, usize)
+    where
+        Item: Hash + Eq,
+    {
+        let mut tree = Self::new(distance_calculator);
+        tree.extend(items);
+        let duplicate_count = tree.remove_duplicates();
+        tree.update();
+        (tree, duplicate_count)
+    }
+
+    /// Rebuilds the tree from scratch out of all the items in it.
     pub fn update(&mut self) {
-        let mut items: Vec<(Item, Distance)> = self
-            .nodes
+        let (mut items, ideal_size_low, ideal_size_high) = self.prepare_update();
+        self.split_subtree(&mut items, 0, ideal_size_low, ideal_size_high, self.depth);
+        self.finish_update(items, ideal_size_low, ideal_size_high);
+    }
+
+    /// Same as `update`, but builds the subtrees on either side of every node in parallel.
+    /// The resulting tree is exactly the same as the one `update` would build.
+    #[cfg(feature = "rayon")]
+    pub fn par_update(&mut self)
+    where
+        Item: Send + Sync,
+        Distance: Send + Sync,
+        DistanceCalculator: Sync,
+    {
+        let (mut items, ideal_size_low, ideal_size_high) = self.prepare_update();
+        self.par_split_subtree(&mut items, 0, ideal_size_low, ideal_size_high, self.depth);
+        self.finish_update(items, ideal_size_low, ideal_size_high);
+    }
+
+    /* Takes all the items out of the tree and works out its new shape. Returns the items
+    along with the ideal sizes of the whole tree, see split_subtree. */
+    fn prepare_update(&mut self) -> (Vec<(Item, Distance, usize)>, usize, usize) {
+        self.radii.clear();
+        self.subtree_lens.clear();
+        let mut items: Vec<(Item, Distance, usize)> = self
+            .items
             .drain(..)
-            .map(|node| (node.vantage_point, Distance::max_value()))
-            .chain(
-                self.leaves
-                    .drain(..)
-                    .map(|item| (item, Distance::max_value())),
-            )
+            .zip(self.ids.drain(..))
+            .map(|(item, id)| (item, Distance::max_value(), id))
             .collect();
+        if let Some(item_order) = self.item_order {
+            items.sort_unstable_by(|a, b| item_order(&a.0, &b.0));
+        }
 
-        /* Depth is the number of layers in the tree, excluding the leaf layer,
-        such that every leaf contains around FLAT_ARRAY_SIZE items.
-        Root node has 2 children, those 2 children have 4 children in total and so on,
-        for a total of 2^depth-1 nodes in a tree, if all layers are full, which is guaranteed
-        in this implementation.
-        The leaf layer is one additional layer below all the nodes, so its size is 2^depth.
-        when queue grows to this size, its guaranteed to contain only data meant for the leaves.
-        Leaves contain an array of items instead of just one because for short arrays linear search
-        isn't less efficient than binary and not having to turn all items into nodes saves time. */
-        let depth = ((items.len() + 1) as f32 / (FLAT_ARRAY_SIZE + 1) as f32)
-            .log2()
-            .ceil() as usize;
-        let leaves_len = 2usize.pow(depth as u32);
+        let depth = depth(items.len(), self.max_leaf_size);
+        self.depth = depth;
+        let leaves_len = leaves_len(depth);
         let nodes_len = leaves_len - 1;
         self.leaf_size = (items.len() - nodes_len) / leaves_len;
-
-        self.nodes.reserve(nodes_len);
-        self.leaves.reserve(leaves_len);
-        let mut queue = VecDeque::with_capacity(leaves_len);
         /* ideal_size_low is the amount of items that would result in a tree with leaves of
         precisely leaf_size length. ideal_size_high is the same, except for leaf_size + 1.
         Actual amount of items is in between these two.
         decrementation_point is the number of leaves with length leaf_size + 1 */
-        let mut ideal_size_low = nodes_len + leaves_len * self.leaf_size;
-        let mut ideal_size_high = nodes_len + leaves_len * (self.leaf_size + 1);
+        let ideal_size_low = nodes_len + leaves_len * self.leaf_size;
+        let ideal_size_high = nodes_len + leaves_len * (self.leaf_size + 1);
         self.decrementation_point = items.len() - ideal_size_low;
-        queue.push_back(items.as_mut_slice());
-        while self.nodes.len() < nodes_len {
-            if queue.len().is_power_of_two() {
-                ideal_size_low = (ideal_size_low - 1) / 2;
-                ideal_size_high = (ideal_size_high - 1) / 2;
-            }
-            /* queue starts with one item and gains two items every iteration, the slices it
-            contains get smaller every iteration, but the the loop will stop before they are
-            smaller than leaf_size, thus the unwraps are safe. */
-            let (vantage_point, items) = queue.pop_front().unwrap().split_last_mut().unwrap();
-            /* We want to give more items to the left side so that the leaves on the right side will have
-            leaf_size long leaves. But we don't want to give the left side so many items that some of its
-            leaves are more than leaf_size + 1 long.*/
-            let split_point = min(items.len() - ideal_size_low, ideal_size_high);
+        (items, ideal_size_low, ideal_size_high)
+    }
 
-            for i in items.iter_mut() {
-                i.1 = (self.distance_calculator)(&vantage_point.0, &i.0)
+    /* Picks which of the items of the node at index becomes its vantage point. Random
+    choices are seeded by the node's index, so that they don't depend on the order nodes
+    are built in. */
+    fn choose_vantage_point(&self, items: &[(Item, Distance, usize)], index: usize) -> usize {
+        match &self.vantage_point_strategy {
+            VantagePointStrategy::Last => items.len() - 1,
+            VantagePointStrategy::Random { seed } => Random::new(*seed, index).below(items.len()),
+            VantagePointStrategy::Earliest => {
+                let (earliest, _) = items
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, (_, _, id))| *id)
+                    .unwrap();
+                earliest
             }
-            /* Put all items that are closer to the vantage_point than the item in split_point to the left */
-            items.select_nth_unstable_by(split_point, |a, b| {
-                if a.1 < b.1 {
-                    Ordering::Less
-                } else {
-                    Ordering::Greater
+            &VantagePointStrategy::MaxSpread {
+                candidates,
+                samples,
+                seed,
+            } => {
+                let mut random = Random::new(seed, index);
+                let mut best = items.len() - 1;
+                let mut best_spread = None;
+                let mut distances = Vec::with_capacity(samples);
+                for _ in 0..min(candidates, items.len()) {
+                    let candidate = random.below(items.len());
+                    distances.clear();
+                    distances.extend((0..samples).map(|_| {
+                        self.distance_calculator
+                            .distance(&items[candidate].0, &items[random.below(items.len())].0)
+                    }));
+                    if distances.len() < 4 {
+                        break;
+                    }
+                    distances.sort_unstable_by(compare);
+                    /* The interquartile range, which unlike the variance needs nothing more
+                    than subtraction of distances */
+                    let spread = distances[distances.len() * 3 / 4].clone()
+                        - distances[distances.len() / 4].clone();
+                    let is_better = match &best_spread {
+                        Some(best) => spread > *best,
+                        None => true,
+                    };
+                    if is_better {
+                        best = candidate;
+                        best_spread = Some(spread);
+                    }
                 }
-            });
-            // All items on the left - and none of those on the right - are within radius
-            let radius = items[split_point].1;
-            let (near_items, far_items) = items.split_at_mut(split_point);
-            queue.push_back(near_items);
-            queue.push_back(far_items);
-            self.nodes.push(Node {
-                vantage_point: vantage_point.0.clone(),
-                radius,
-            });
+                best
+            }
+            VantagePointStrategy::PreselectedVantagePoints(vantage_points) => vantage_points
+                .get(index)
+                .and_then(|&vantage_point| items.iter().position(|(_, _, id)| *id == vantage_point))
+                .unwrap_or(items.len() - 1),
         }
-        /* Put the remaining items in the leaves */
-        self.leaves.extend(
-            queue
-                .into_iter()
-                .flat_map(|items| items.into_iter().map(|(item, _)| item.clone())),
-        );
-        self.is_updated = true;
     }
 
-    pub fn insert(&mut self, item: Item) {
-        self.leaves.push(item);
-        self.is_updated = false;
-    }
+    /* Turns one of items into a vantage point, see choose_vantage_point, and moves the rest of
+    them to either side of it, returning both sides. The ideal sizes are those of the subtree
+    the items make up, the sides get the ideal sizes of its children. The vantage point no
+    longer needs its distance to the parent's vantage point, so its radius is kept in its place. */
+    #[allow(clippy::type_complexity)]
+    fn split_node<'a>(
+        &self,
+        items: &'a mut [(Item, Distance, usize)],
+        index: usize,
+        ideal_size_low: usize,
+        ideal_size_high: usize,
+    ) -> (
+        &'a mut [(Item, Distance, usize)],
+        &'a mut [(Item, Distance, usize)],
+    ) {
+        let chosen = self.choose_vantage_point(items, index);
+        let last = items.len() - 1;
+        items.swap(chosen, last);
+        /* The slices get smaller with every layer, but the tree's depth is such that they
+        never get smaller than leaf_size, thus the unwrap is safe. */
+        let (vantage_point, items) = items.split_last_mut().unwrap();
+        let split_point = split_point(items.len(), ideal_size_low, ideal_size_high);
 
-    pub fn extend<I: IntoIterator<Item = Item>>(&mut self, items: I) {
-        self.leaves.extend(items.into_iter());
-        self.is_updated = false;
+        let measure = |vantage_point: &Item, items: &mut [(Item, Distance, usize)]| {
+            for i in items.iter_mut() {
+                i.1 = self.distance_calculator.distance(vantage_point, &i.0);
+                debug_assert!(is_comparable(&i.1), "distance_calculator returned NaN");
+            }
+        };
+        measure(&vantage_point.0, items);
+        /* If all the items are exactly as far from the vantage point, as they are from the
+        center of a circle they lie on, the split would still be even, but searches would
+        learn nothing from which side of it they are on. Try one of them instead, once. */
+        if items.len() > 1
+            && items
+                .iter()
+                .all(|i| compare(&i.1, &items[0].1) == Ordering::Equal)
+        {
+            std::mem::swap(&mut vantage_point.0, &mut items[0].0);
+            std::mem::swap(&mut vantage_point.2, &mut items[0].2);
+            measure(&vantage_point.0, items);
+        }
+        /* Put all items that are closer to the vantage_point than the item in split_point to the
+        left. Items that are exactly as far away as it may end up on either side, which keeps the
+        split even no matter how many of them there are. */
+        items.select_nth_unstable_by(split_point, |a, b| compare(&a.1, &b.1));
+        /* All items on the left are within radius and all items on the right are at least radius
+        away, which is all that searching relies on */
+        vantage_point.1 = items[split_point].1.clone();
+        items.split_at_mut(split_point)
     }
 
-    pub fn len(&self) -> usize {
-        self.nodes.len() + self.leaves.len()
+    /* Splits items into a subtree with depth layers of nodes. Every split happens in place,
+    so afterwards the items are laid out like the tree they make up, each vantage point
+    following the two sides split around it. */
+    fn split_subtree(
+        &self,
+        items: &mut [(Item, Distance, usize)],
+        index: usize,
+        ideal_size_low: usize,
+        ideal_size_high: usize,
+        depth: usize,
+    ) {
+        if depth > 0 {
+            let ideal_size_low = (ideal_size_low - 1) / 2;
+            let ideal_size_high = (ideal_size_high - 1) / 2;
+            let (near_items, far_items) =
+                self.split_node(items, index, ideal_size_low, ideal_size_high);
+            let index = index * 2;
+            self.split_subtree(
+                near_items,
+                index + 1,
+                ideal_size_low,
+                ideal_size_high,
+                depth - 1,
+            );
+            self.split_subtree(
+                far_items,
+                index + 2,
+                ideal_size_low,
+                ideal_size_high,
+                depth - 1,
+            );
+        }
     }
 
-    fn get_leaf(&self, index: &mut usize) -> &[Item] {
-        /* Leaves can have length leaf_size or leaf_size + 1.
-        All the big leaves have an index smaller than decrementation_point */
-        &self.leaves[if *index < self.decrementation_point {
-            *index *= self.leaf_size + 1;
-            *index..*index + self.leaf_size + 1
-        } else {
-            *index = (*index - self.decrementation_point) * self.leaf_size
-                + self.decrementation_point * (self.leaf_size + 1);
-            *index..*index + self.leaf_size
-        }]
+    #[cfg(feature = "rayon")]
+    fn par_split_subtree(
+        &self,
+        items: &mut [(Item, Distance, usize)],
+        index: usize,
+        ideal_size_low: usize,
+        ideal_size_high: usize,
+        depth: usize,
+    ) where
+        Item: Send + Sync,
+        Distance: Send + Sync,
+        DistanceCalculator: Sync,
+    {
+        if depth > 0 {
+            let ideal_size_low = (ideal_size_low - 1) / 2;
+            let ideal_size_high = (ideal_size_high - 1) / 2;
+            let (near_items, far_items) =
+                self.split_node(items, index, ideal_size_low, ideal_size_high);
+            let index = index * 2;
+            rayon::join(
+                || {
+                    let index = index + 1;
+                    self.par_split_subtree(
+                        near_items,
+                        index,
+                        ideal_size_low,
+                        ideal_size_high,
+                        depth - 1,
+                    )
+                },
+                || {
+                    let index = index + 2;
+                    self.par_split_subtree(
+                        far_items,
+                        index,
+                        ideal_size_low,
+                        ideal_size_high,
+                        depth - 1,
+                    )
+                },
+            );
+        }
     }
 
-    pub fn find_nearest_neighbor(&mut self, needle: &Item) -> Option<(Distance, Item)> {
-        if !self.is_updated {
-            self.update();
-        }
-        let mut index = 0;
-        let mut nearest_neighbor = index;
-        let mut threshold = Distance::max_value();
-        let mut unexplored = Vec::with_capacity(self.depth);
-        while let Some(node) = match self.nodes.get(index) {
-            Some(node) => Some(node),
-            None => {
-                /* index didn't point to a node, it is therefore guaranteed to point to a leaf. */
-                index -= self.nodes.len();
-                for (inner_index, item) in self.get_leaf(&mut index).iter().enumerate() {
-                    let distance = (self.distance_calculator)(needle, item);
-                    if distance < threshold {
-                        nearest_neighbor = index + inner_index + self.nodes.len();
-                        threshold = distance;
-                    }
-                }
-                loop {
-                    if let Some((mut potential_index, distance_to_boundary)) = unexplored.pop() {
-                        /* At this point it is guaranteed that the other child of potential_index's
-                        parent has been explored. Therefore, all the nodes on the other
-                        side of the parent's boundary (defined by its radius) have been considered.
-                        potential_index can possibly point to a viable neighbor candidate only if the
-                        current nearest neighbor's distance is so large, that it crosses over the boundary,
-                        meaning that there may be an item pointed to by potential_index that is closer
-                        to needle than current nearest neighbor. */
-                        if threshold > distance_to_boundary {
-                            if let Some(potential_node) = self.nodes.get(potential_index) {
-                                index = potential_index;
-                                break Some(potential_node);
-                            } else {
-                                potential_index -= self.nodes.len();
-                                for (inner_index, item) in
-                                    self.get_leaf(&mut potential_index).iter().enumerate()
-                                {
-                                    let distance = (self.distance_calculator)(needle, item);
-                                    if distance < threshold {
-                                        nearest_neighbor =
-                                            potential_index + inner_index + self.nodes.len();
-                                        threshold = distance;
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        break None;
-                    }
-                }
+    /* Moves the split items back into the tree, in the order nodes and leaves are stored in.
+    The nodes are stored layer by layer, so the items are walked through breadth first.
+    When the queue grows to 2^depth ranges, it's guaranteed to contain only the leaves,
+    in order. */
+    fn finish_update(
+        &mut self,
+        mut items: Vec<(Item, Distance, usize)>,
+        mut ideal_size_low: usize,
+        mut ideal_size_high: usize,
+    ) {
+        let leaves_len = leaves_len(self.depth);
+        let nodes_len = leaves_len - 1;
+        /* order[position] is where the item that belongs at position currently is */
+        let mut order = Vec::with_capacity(items.len());
+        let mut queue = VecDeque::with_capacity(leaves_len);
+        queue.push_back(0..items.len());
+        self.subtree_lens.reserve(nodes_len);
+        while order.len() < nodes_len {
+            if queue.len().is_power_of_two() {
+                ideal_size_low = (ideal_size_low - 1) / 2;
+                ideal_size_high = (ideal_size_high - 1) / 2;
             }
-        } {
-            let distance = (self.distance_calculator)(needle, &node.vantage_point);
-            if distance < threshold {
-                nearest_neighbor = index;
-                threshold = distance;
-            }
-            index = if distance < node.radius {
-                /* Needle is within node's radius, therefore its nearest neigbors
-                are likely to be within it too. The left tree, at index*2+1, contains
-                all child nodes within node's radius, so search that tree and add
-                the right tree - at index*2+2 - to the stack of unexplored nodes along
-                with the distance between needle and current node's boundary. */
-                index *= 2;
-                unexplored.push((index + 2, node.radius - distance));
-                index + 1
-            } else {
-                index *= 2;
-                unexplored.push((index + 1, distance - node.radius));
-                index + 2
-            };
+            let range = queue.pop_front().unwrap();
+            self.subtree_lens.push(range.len());
+            let vantage_point = range.end - 1;
+            let split_point = range.start
+                + split_point(vantage_point - range.start, ideal_size_low, ideal_size_high);
+            queue.push_back(range.start..split_point);
+            queue.push_back(split_point..vantage_point);
+            order.push(vantage_point);
         }
-        if threshold < Distance::max_value() {
-            Some((
-                threshold,
-                // Map the index to an item
-                if nearest_neighbor < self.nodes.len() {
-                    self.nodes[nearest_neighbor].vantage_point.clone()
-                } else {
-                    self.leaves[nearest_neighbor - self.nodes.len()].clone()
-                },
-            ))
-        } else {
-            None
+        order.extend(queue.into_iter().flatten());
+        permute(&mut items, &mut order);
+        self.radii.reserve(nodes_len);
+        self.items.reserve(items.len());
+        self.ids.reserve(items.len());
+        for (position, (item, radius, id)) in items.into_iter().enumerate() {
+            if position < nodes_len {
+                self.radii.push(radius);
+            }
+            self.items.push(item);
+            self.ids.push(id);
         }
+        self.is_updated = true;
     }
 
-    pub fn find_k_nearest_neighbors(&mut self, needle: &Item, k: usize) -> Vec<(Distance, Item)> {
-        fn consider_item<Distance: PartialOrd + Bounded + Copy>(
-            index: usize,
-            distance: Distance,
-            nearest_neighbors: &mut Vec<(Distance, usize)>,
-        ) -> Distance {
-            if nearest_neighbors.len() < nearest_neighbors.capacity() {
-                nearest_neighbors.push((distance, index));
-                if nearest_neighbors.len() == nearest_neighbors.capacity() {
-                    /* Now that nearest_neigbors has reached its capacity,
-                    we only want to add a new item if it's closer to needle
-                    than an item in nearest_neighbors, so we set the threshold
-                    to distance of farthest neighbor in nearest_neigbors */
-                    nearest_neighbors.sort_by(|a, b| {
-                        if a.0 < b.0 {
-                            Ordering::Less
-                        } else {
-                            Ordering::Greater
-                        }
-                    });
-                    nearest_neighbors.last().unwrap().0
-                } else {
-                    return Distance::max_value();
-                }
-            } else {
-                /* Since nearest_neigbors is guaranteed to be sorted by distance
-                of its members to the needle at this point, its last member
-                has the greatest (least desirable) distance to the needle.*/
-                nearest_neighbors.pop();
-                nearest_neighbors.insert(
-                    // Keep the vec sorted by inserting at index specified by binary search
-                    nearest_neighbors
-                        .binary_search_by(|(neighbor_distance, _)| {
-                            if neighbor_distance < &distance {
-                                Ordering::Less
-                            } else {
-                                Ordering::Greater
-                            }
-                        })
-                        .unwrap_or_else(|x| x),
-                    (distance, index),
-                );
-                nearest_neighbors.last().unwrap().0
-            }
+    /* Builds the subtree at index, counting nodes and leaves together, anew out of the items
+    in it, leaving the rest of the tree as it is. The subtree's items are swapped into one
+    stretch of the tree ending with its leaves, in place of the items that aren't its own,
+    split like update would split them and then put back where the nodes and leaves of the
+    subtree are stored, swapping the other items back too. */
+    fn rebuild_subtree(&mut self, index: usize) {
+        if index >= self.radii.len() {
+            /* Leaves have no order to keep */
+            return;
         }
-        if !self.is_updated {
-            self.update();
+        let layer = (usize::BITS - 1 - (index + 1).leading_zeros()) as usize;
+        let depth = self.depth - layer;
+        let leaves_len = leaves_len(depth);
+        let first_leaf = (index + 1) * leaves_len - self.leaf_count();
+        let leaf_start = |leaf: usize| {
+            self.radii.len() + leaf * self.leaf_size + min(leaf, self.decrementation_point)
+        };
+        let (start, end) = (leaf_start(first_leaf), leaf_start(first_leaf + leaves_len));
+        /* The subtree's nodes, layer by layer, which is the order they are stored in */
+        let nodes: Vec<usize> = (0..depth)
+            .flat_map(|layer| {
+                let first = ((index + 1) << layer) - 1;
+                first..first + (1 << layer)
+            })
+            .collect();
+        let len = nodes.len() + end - start;
+        let stretch_start = end - len;
+        let outside = nodes.iter().copied().filter(|&node| node < stretch_start);
+        let free = (stretch_start..start).filter(|position| nodes.binary_search(position).is_err());
+        let swaps: Vec<(usize, usize)> = outside.zip(free).collect();
+        for &(node, position) in &swaps {
+            self.items.swap(node, position);
+            self.ids.swap(node, position);
         }
-        let mut nearest_neighbors = Vec::with_capacity(k);
-        let mut index = 0;
-        let mut threshold = Distance::max_value();
-        let mut unexplored = Vec::with_capacity(self.depth);
-        while let Some(node) = match self.nodes.get(index) {
-            Some(node) => Some(node),
-            None => {
-                index -= self.nodes.len();
-                for (inner_index, item) in self.get_leaf(&mut index).iter().enumerate() {
-                    let distance = (self.distance_calculator)(needle, item);
-                    if distance < threshold {
-                        threshold = consider_item(
-                            index + inner_index + self.nodes.len(),
-                            distance,
-                            &mut nearest_neighbors,
-                        );
-                    }
-                }
-                loop {
-                    if let Some((mut potential_index, distance_to_boundary)) = unexplored.pop() {
-                        if threshold > distance_to_boundary {
-                            if let Some(potential_node) = self.nodes.get(potential_index) {
-                                index = potential_index;
-                                break Some(potential_node);
-                            } else {
-                                potential_index -= self.nodes.len();
-                                for (inner_index, item) in
-                                    self.get_leaf(&mut potential_index).iter().enumerate()
-                                {
-                                    let distance = (self.distance_calculator)(needle, item);
-                                    if distance < threshold {
-                                        threshold = consider_item(
-                                            potential_index + inner_index + self.nodes.len(),
-                                            distance,
-                                            &mut nearest_neighbors,
-                                        );
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        break None;
-                    }
-                }
-            }
-        } {
-            let distance = (self.distance_calculator)(needle, &node.vantage_point);
-            if distance < threshold {
-                threshold = consider_item(index, distance, &mut nearest_neighbors);
+        let mut items: Vec<(Item, Distance, usize)> = self
+            .items
+            .drain(stretch_start..end)
+            .zip(self.ids.drain(stretch_start..end))
+            .map(|(item, id)| (item, Distance::max_value(), id))
+            .collect();
+
+        let mut ideal_size_low = nodes.len() + leaves_len * self.leaf_size;
+        let mut ideal_size_high = ideal_size_low + leaves_len;
+        self.split_subtree(&mut items, index, ideal_size_low, ideal_size_high, depth);
+        /* The same walk as in finish_update, except that nodes whose items were swapped
+        into the stretch go to where they were swapped to */
+        let mut order = vec![0; len];
+        let mut queue = VecDeque::with_capacity(leaves_len);
+        queue.push_back(0..len);
+        for &node in &nodes {
+            if queue.len().is_power_of_two() {
+                ideal_size_low = (ideal_size_low - 1) / 2;
+                ideal_size_high = (ideal_size_high - 1) / 2;
             }
-            index = if distance < node.radius {
-                index *= 2;
-                unexplored.push((index + 2, node.radius - distance));
-                index + 1
-            } else {
-                index *= 2;
-                unexplored.push((index + 1, distance - node.radius));
-                index + 2
-            };
+            let range = queue.pop_front().unwrap();
+            self.subtree_lens[node] = range.len();
+            let vantage_point = range.end - 1;
+            let split_point = range.start
+                + split_point(vantage_point - range.start, ideal_size_low, ideal_size_high);
+            queue.push_back(range.start..split_point);
+            queue.push_back(split_point..vantage_point);
+            self.radii[node] = items[vantage_point].1.clone();
+            let position = swaps
+                .iter()
+                .find(|(swapped, _)| *swapped == node)
+                .map_or(node, |(_, position)| *position);
+            order[position - stretch_start] = vantage_point;
+        }
+        for (position, item) in (start - stretch_start..).zip(queue.into_iter().flatten()) {
+            order[position] = item;
+        }
+        permute(&mut items, &mut order);
+        let (items, ids): (Vec<Item>, Vec<usize>) =
+            items.into_iter().map(|(item, _, id)| (item, id)).unzip();
+        self.items.splice(stretch_start..stretch_start, items);
+        self.ids.splice(stretch_start..stretch_start, ids);
+        for &(node, position) in &swaps {
+            self.items.swap(node, position);
+            self.ids.swap(node, position);
         }
-        nearest_neighbors
-            .into_iter()
-            .map(|(distance, index)| {
-                (
-                    distance,
-                    if index < self.nodes.len() {
-                        self.nodes[index].vantage_point.clone()
-                    } else {
-                        self.leaves[index - self.nodes.len()].clone()
-                    },
-                )
-            })
-            .collect()
     }
 
-    pub fn find_neighbors_within_radius(
-        &mut self,
-        needle: &Item,
-        threshold: Distance,
-    ) -> Vec<(Distance, Item)> {
+    /// Builds the tree if items have been added since it was last built, otherwise does nothing.
+    /// Queries call this themselves, but calling it up front moves the cost of building out
+    /// of the first query.
+    pub fn build(&mut self) {
         if !self.is_updated {
             self.update();
         }
-        let mut nearest_neighbors = Vec::new();
+    }
+
+    /// Same as `build`, but returns whether the tree had to be rebuilt, which happens when
+    /// more items are waiting outside of it than `with_max_pending` allows. Calling this
+    /// after a batch of inserts keeps queries from slowing down without rebuilding the tree
+    /// on every insert.
+    pub fn rebalance_if_needed(&mut self) -> bool {
+        let needed = !self.is_updated;
+        self.build();
+        needed
+    }
+
+    /* Items added since the last update are appended to the leaves, past the ones that
+    make up the tree. Queries compare them to the needle one by one, so that a few inserts
+    in between queries don't force the whole tree to be rebuilt every time. Once there are
+    more than max_pending of them, the tree is updated before the next query. */
+    fn pending_start(&self) -> usize {
+        (self.radii.len() + 1) * self.leaf_size + self.decrementation_point
+    }
+
+    /* The items past the vantage points */
+    fn leaves(&self) -> &[Item] {
+        &self.items[self.radii.len()..]
+    }
+
+    /* Marks the tree to be updated before the next query once too many items are pending */
+    fn check_pending(&mut self) {
+        if self.leaves().len() - self.pending_start() > self.max_pending {
+            self.is_updated = false;
+        }
+    }
+
+    /// Adds an item to the tree. This never rebuilds the whole tree. If the item belongs in
+    /// the same subtree as the leaf that grows next and that subtree holds no more items than
+    /// `with_max_pending` allows to wait, only that subtree is rebuilt with the item in it.
+    /// Otherwise the item is only compared to needles one by one until enough items have been
+    /// added for the next query to rebuild the tree first, so inserting n items one at a time
+    /// costs at most one rebuild at the next query, not n of them.
+    pub fn insert(&mut self, item: Item) {
+        self.try_insert(item);
+    }
+
+    /* The subtree that an item can be put into without rebuilding the rest of the tree, if
+    any. Every subtree's size is fixed by the shape of the whole tree, and the only leaf that
+    can take another item without changing it is the one at decrementation_point, the first
+    of the small ones. So the item has to belong in a subtree that holds that leaf as well,
+    the smallest of which is where the paths to the two leaves part. Deterministic trees
+    are left alone, as their shape would come to depend on the order items were added in. */
+    fn subtree_to_grow(&self, item: &Item) -> Option<usize> {
+        if !self.is_updated
+            || self.item_order.is_some()
+            || depth(
+                self.radii.len() + self.pending_start() + 1,
+                self.max_leaf_size,
+            ) != self.depth
+        {
+            return None;
+        }
         let mut index = 0;
-        let mut unexplored = Vec::with_capacity(self.depth);
-        while let Some(node) = match self.nodes.get(index) {
-            Some(node) => Some(node),
-            None => {
-                index -= self.nodes.len();
-                for (inner_index, item) in self.get_leaf(&mut index).iter().enumerate() {
-                    let distance = (self.distance_calculator)(needle, item);
-                    if distance <= threshold {
-                        nearest_neighbors.push((distance, index + inner_index + self.nodes.len()));
-                    }
-                }
-                loop {
-                    if let Some(mut potential_index) = unexplored.pop() {
-                        if let Some(potential_node) = self.nodes.get(potential_index) {
-                            index = potential_index;
-                            break Some(potential_node);
-                        } else {
-                            potential_index -= self.nodes.len();
-                            for (inner_index, item) in
-                                self.get_leaf(&mut potential_index).iter().enumerate()
-                            {
-                                let distance = (self.distance_calculator)(needle, item);
-                                if distance <= threshold {
-                                    nearest_neighbors.push((
-                                        distance,
-                                        potential_index + inner_index + self.nodes.len(),
-                                    ));
-                                }
-                            }
-                        }
-                    } else {
-                        break None;
-                    }
-                }
-            }
-        } {
-            let distance = (self.distance_calculator)(needle, &node.vantage_point);
-            if distance <= threshold {
-                nearest_neighbors.push((distance, index));
-            }
-            index = if distance < node.radius {
-                /* We're only interested in nodes than lie within threshold distance to the needle.
-                Needle lies within left child's boundary which we will search immediately.
-                Therefore, we should only add the right child to the queue only if the
-                threshold is so large, that it crosses over the boundary. */
-                index *= 2;
-                if threshold >= node.radius - distance {
-                    unexplored.push(index + 2);
-                }
-                index + 1
+        while index < self.radii.len() {
+            let distance = self.distance_calculator.distance(&self.items[index], item);
+            debug_assert!(is_comparable(&distance), "distance_calculator returned NaN");
+            index = if distance < self.radii[index] {
+                index * 2 + 1
             } else {
-                index *= 2;
-                if threshold >= distance - node.radius {
-                    unexplored.push(index + 1);
-                }
-                index + 2
+                index * 2 + 2
             };
         }
-        nearest_neighbors.sort_by(|a, b| {
-            if a.0 < b.0 {
-                Ordering::Less
-            } else {
-                Ordering::Greater
-            }
-        });
-        nearest_neighbors
-            .into_iter()
-            .map(|(distance, index)| {
-                (
-                    distance,
-                    if index < self.nodes.len() {
-                        self.nodes[index].vantage_point.clone()
-                    } else {
-                        self.leaves[index - self.nodes.len()].clone()
-                    },
-                )
-            })
-            .collect()
+        /* Both leaves are on the same layer, so their ancestors are too */
+        let mut growing = self.radii.len() + self.decrementation_point;
+        while index != growing {
+            index = (index - 1) / 2;
+            growing = (growing - 1) / 2;
+        }
+        if self.subtree_len(index) <= self.max_pending {
+            Some(index)
+        } else {
+            None
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /* Puts item into the subtree at index, see subtree_to_grow, and rebuilds the subtree.
+    The item is added to the end of the growing leaf, which moves the leaves after it and the
+    pending items by one, just like the one more big leaf shifts where they start. */
+    fn insert_into_subtree(&mut self, index: usize, item: Item) {
+        let position = self.radii.len()
+            + (self.decrementation_point + 1) * self.leaf_size
+            + self.decrementation_point;
+        self.items.insert(position, item);
+        self.ids.insert(position, self.next_id);
+        self.next_id += 1;
+        self.decrementation_point += 1;
+        if self.decrementation_point == self.leaf_count() {
+            self.leaf_size += 1;
+            self.decrementation_point = 0;
+        }
+        let mut ancestor = index;
+        while ancestor > 0 {
+            ancestor = (ancestor - 1) / 2;
+            self.subtree_lens[ancestor] += 1;
+        }
+        self.rebuild_subtree(index);
+    }
 
-    #[test]
-    fn nearest_neigbor_search() {
-        let points = vec![
-            (2.0, 3.0),
-            (0.0, 1.0),
-            (4.0, 5.0),
-            (45.0, 43.0),
-            (21.0, 20.0),
-            (39.0, 44.0),
-            (96.0, 46.0),
-            (95.0, 32.0),
-            (14.0, 63.0),
-            (19.0, 81.0),
-            (66.0, 36.0),
-            (26.0, 64.0),
-            (10.0, 21.0),
-            (92.0, 84.0),
-            (31.0, 55.0),
-            (59.0, 4.0),
-            (43.0, 11.0),
-            (87.0, 56.0),
-            (76.0, 52.0),
-            (10.0, 55.0),
-            (64.0, 97.0),
-            (6.0, 4.0),
-            (10.0, 68.0),
-            (9.0, 8.0),
-            (60.0, 61.0),
-            (22.0, 26.0),
-            (79.0, 52.0),
-            (29.0, 98.0),
-            (88.0, 60.0),
-            (29.0, 97.0),
-            (42.0, 20.0),
-            (5.0, 57.0),
-            (81.0, 58.0),
-            (22.0, 70.0),
-            (44.0, 47.0),
-            (16.0, 6.0),
-            (2.0, 19.0),
-            (26.0, 59.0),
-            (45.0, 34.0),
-            (10.0, 37.0),
-            (8.0, 46.0),
-            (38.0, 6.0),
-            (98.0, 83.0),
-            (18.0, 79.0),
-            (3.0, 81.0),
-            (77.0, 40.0),
-            (82.0, 93.0),
-            (1.0, 65.0),
-            (51.0, 86.0),
-            (34.0, 10.0),
-            (91.0, 16.0),
-            (28.0, 33.0),
-            (5.0, 93.0),
-        ];
-        let mut tree = VPTree::new(|a: &(f32, f32), b| {
-            ((a.0 - b.0 as f32).powi(2) + (a.1 - b.1 as f32).powi(2)).sqrt()
-        });
-        tree.extend(points);
+    /// Same as `insert`, but tells whether the item went into the tree or waits outside of
+    /// it, and whether the tree now has to be rebuilt, so that the rebuild can be done with
+    /// `build` at a convenient time rather than by the next query.
+    pub fn try_insert(&mut self, item: Item) -> InsertOutcome {
+        if let Some(node_index) = self.subtree_to_grow(&item) {
+            self.insert_into_subtree(node_index, item);
+            return InsertOutcome::RebuiltSubtree { node_index };
+        }
+        self.items.push(item);
+        self.ids.push(self.next_id);
+        self.next_id += 1;
+        self.check_pending();
+        if self.is_updated {
+            InsertOutcome::AppendedToLeaf
+        } else {
+            InsertOutcome::TriggeredRebuild
+        }
+    }
 
-        let expected = Some((13.453624, (60.0, 61.0)));
-        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
-        assert_eq!(actual, expected);
+    /// Adds all the items to the tree, same as calling `insert` with every one of them.
+    /// The tree is rebuilt at most once, before the next query, or right away by `build`.
+    pub fn extend<I: IntoIterator<Item = Item>>(&mut self, items: I) {
+        self.items.extend(items);
+        let next_id = self.next_id + self.items.len() - self.ids.len();
+        self.ids.extend(self.next_id..next_id);
+        self.next_id = next_id;
+        self.check_pending();
+    }
 
-        let expected = vec![(4.2426405, (91.0, 16.0)), (13.038404, (95.0, 32.0))];
-        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
-        assert_eq!(actual, expected);
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
 
-        let actual = tree.find_neighbors_within_radius(&(94.0, 19.0), 13.038404);
-        assert_eq!(actual, expected);
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        let expected = vec![
-            (4.472136, (5.0, 57.0)),
-            (6.708204, (10.0, 55.0)),
-            (7.2111025, (1.0, 65.0)),
-            (7.28011, (14.0, 63.0)),
-            (7.615773, (10.0, 68.0)),
-            (15.033297, (8.0, 46.0)),
-            (17.492855, (22.0, 70.0)),
-            (19.104973, (26.0, 59.0)),
-            (19.235384, (26.0, 64.0)),
-            (20.396078, (3.0, 81.0)),
-        ];
-        let actual = tree.find_k_nearest_neighbors(&(7.0, 61.0), 10);
-        assert_eq!(actual, expected);
+    /// How far apart `a` and `b` are, as the tree measures it.
+    pub fn distance(&self, a: &Item, b: &Item) -> Distance {
+        self.distance_calculator.distance(a, b)
+    }
 
-        let actual = tree.find_neighbors_within_radius(&(7.0, 61.0), 20.396078);
-        assert_eq!(actual, expected);
+    /// The distance calculator the tree measures distances with.
+    pub fn metric(&self) -> &DistanceCalculator {
+        &self.distance_calculator
+    }
 
-        let expected = vec![
-            (3.6055512, (87.0, 56.0)),
-            (5.0, (81.0, 58.0)),
-            (5.3851647, (79.0, 52.0)),
-            (7.2111025, (88.0, 60.0)),
-            (8.246211, (76.0, 52.0)),
-            (14.422205, (96.0, 46.0)),
-            (15.652476, (77.0, 40.0)),
-            (24.596748, (95.0, 32.0)),
-            (25.0, (60.0, 61.0)),
-            (25.455845, (66.0, 36.0)),
-            (31.04835, (92.0, 84.0)),
-            (32.202484, (98.0, 83.0)),
-            (38.63936, (91.0, 16.0)),
-            (39.051247, (82.0, 93.0)),
-            (40.5216, (45.0, 43.0)),
-            (40.60788, (44.0, 47.0)),
-            (43.829212, (45.0, 34.0)),
-            (45.96738, (51.0, 86.0)),
-            (46.09772, (39.0, 44.0)),
-            (47.423622, (64.0, 97.0)),
-            (53.009434, (31.0, 55.0)),
-            (54.037025, (42.0, 20.0)),
-            (55.9017, (59.0, 4.0)),
-            (58.21512, (26.0, 59.0)),
-            (58.855755, (26.0, 64.0)),
-            (59.413803, (43.0, 11.0)),
-            (59.808025, (28.0, 33.0)),
-            (64.03124, (22.0, 70.0)),
-            (66.48308, (38.0, 6.0)),
-            (66.6033, (34.0, 10.0)),
-            (68.0294, (22.0, 26.0)),
-            (69.81404, (29.0, 97.0)),
-            (70.38466, (19.0, 81.0)),
-            (70.434364, (29.0, 98.0)),
-            (70.5762, (18.0, 79.0)),
-            (70.5762, (14.0, 63.0)),
-            (71.5891, (21.0, 20.0)),
-            (74.00676, (10.0, 55.0)),
-            (75.31268, (10.0, 68.0)),
-            (75.9276, (10.0, 37.0)),
-            (76.41989, (8.0, 46.0)),
-            (79.05694, (5.0, 57.0)),
-            (81.02469, (10.0, 21.0)),
-            (83.23461, (16.0, 6.0)),
-            (83.725746, (1.0, 65.0)),
-            (85.3815, (3.0, 81.0)),
-            (87.982956, (9.0, 8.0)),
-            (88.10221, (5.0, 93.0)),
-            (89.157166, (2.0, 19.0)),
-            (92.64988, (6.0, 4.0)),
-        ];
-        let actual = tree.find_k_nearest_neighbors(&(84.0, 54.0), 50);
-        assert_eq!(actual, expected);
+    /// The number of layers of nodes above the leaves, as of the last time the tree was built.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
 
-        let actual = tree.find_neighbors_within_radius(&(84.0, 54.0), 92.64988);
-        assert_eq!(actual, expected);
+    /// The radius of the root node, which is roughly the median distance between items,
+    /// or None if the tree was too small to have any nodes the last time it was built.
+    pub fn root_radius(&self) -> Option<Distance> {
+        self.radii.first().cloned()
     }
-    #[test]
-    fn utility_functions() {
-        let points = vec![(2.0, 3.0), (0.0, 1.0), (4.0, 5.0)];
-        let mut tree = VPTree::new(|a: &(f32, f32), b| {
-            ((a.0 - b.0 as f32).powi(2) + (a.1 - b.1 as f32).powi(2)).sqrt()
-        });
-        tree.extend(points);
-        assert_eq!(tree.len(), 3);
-        tree.insert((9.0, 8.0));
-        assert_eq!(tree.len(), 4);
-        tree.extend(vec![(19.0, 81.0), (66.0, 36.0)]);
-        assert_eq!(tree.len(), 6);
+
+    /// The number of leaves below the nodes, as of the last time the tree was built.
+    /// Each of them holds `len() / leaf_count()` items or so.
+    pub fn leaf_count(&self) -> usize {
+        self.radii.len() + 1
     }
-    #[test]
-    fn tiny_tree() {
-        let points = vec![
-            (2.0, 3.0),
-            (0.0, 1.0),
-            (4.0, 5.0),
-            (45.0, 43.0),
-            (21.0, 20.0),
-            (39.0, 44.0),
-            (96.0, 46.0),
-            (95.0, 32.0),
-            (14.0, 63.0),
-            (19.0, 81.0),
-            (66.0, 36.0),
-            (26.0, 64.0),
-            (10.0, 21.0),
-            (92.0, 84.0),
-            (31.0, 55.0),
-            (59.0, 4.0),
-            (43.0, 11.0),
-            (87.0, 56.0),
-            (76.0, 52.0),
-            (10.0, 55.0),
-            (64.0, 97.0),
-            (6.0, 4.0),
-            (10.0, 68.0),
-            (9.0, 8.0),
-            (60.0, 61.0),
-            (22.0, 26.0),
-            (79.0, 52.0),
-            (29.0, 98.0),
-            (88.0, 60.0),
-            (29.0, 97.0),
-            (42.0, 20.0),
-            (5.0, 57.0),
-            (81.0, 58.0),
-            (22.0, 70.0),
-            (44.0, 47.0),
-            (16.0, 6.0),
-            (2.0, 19.0),
-            (26.0, 59.0),
-            (45.0, 34.0),
-            (10.0, 37.0),
-            (8.0, 46.0),
-            (38.0, 6.0),
-            (98.0, 83.0),
-            (18.0, 79.0),
-            (3.0, 81.0),
-            (77.0, 40.0),
-            (82.0, 93.0),
-            (1.0, 65.0),
-            (51.0, 86.0),
-            (34.0, 10.0),
-            (91.0, 16.0),
-            (28.0, 33.0),
-            (5.0, 93.0),
-        ];
-        let mut tree = VPTree::new(|a: &(f32, f32), b| {
-            ((a.0 - b.0 as f32).powi(2) + (a.1 - b.1 as f32).powi(2)).sqrt()
-        });
-        tree.extend(points[0..3].to_vec());
 
-        let expected = Some((92.63369, (4.0, 5.0)));
-        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
-        assert_eq!(actual, expected);
+    /// The least, the most and the average number of items in a leaf, leaving out inserted
+    /// items that wait outside of the tree. Leaves never differ in size by more than one item.
+    pub fn leaf_occupancy(&self) -> (usize, usize, f64) {
+        let largest = if self.decrementation_point > 0 {
+            self.leaf_size + 1
+        } else {
+            self.leaf_size
+        };
+        let mean =
+            self.leaf_size as f64 + self.decrementation_point as f64 / self.leaf_count() as f64;
+        (self.leaf_size, largest, mean)
+    }
 
-        let expected = vec![(91.08238, (4.0, 5.0)), (93.38094, (2.0, 3.0))];
-        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
-        assert_eq!(actual, expected);
+    /// Moves all the items into a new tree that measures distances with `distance_calculator`
+    /// instead and builds it.
+    pub fn map_metric<NewDistance, NewDistanceCalculator>(
+        self,
+        distance_calculator: NewDistanceCalculator,
+    ) -> VPTree<Item, NewDistance, NewDistanceCalculator>
+    where
+        NewDistance: Clone + PartialOrd + Bounded + Sub<Output = NewDistance>,
+        NewDistanceCalculator: Metric<Item, Distance = NewDistance>,
+    {
+        let mut tree = VPTree::new(distance_calculator)
+            .with_vantage_point_strategy(self.vantage_point_strategy.clone())
+            .with_max_pending(self.max_pending)
+            .with_leaf_size(self.max_leaf_size);
+        tree.item_order = self.item_order;
+        tree.items = self.items;
+        tree.ids = self.ids;
+        tree.next_id = self.next_id;
+        tree.update();
+        tree
+    }
 
-        let mut tree = VPTree::new(|a: &(f32, f32), b| {
-            ((a.0 - b.0 as f32).powi(2) + (a.1 - b.1 as f32).powi(2)).sqrt()
-        });
-        tree.extend(points[0..2].to_vec());
+    /// Calls `f` with every item in the tree, in no particular order, letting it change
+    /// them without rebuilding the tree. **`f` must not change how far any item is from
+    /// any other**, otherwise queries will silently return wrong results: it is meant for
+    /// updating parts of items that the distance calculator ignores, such as labels.
+    pub fn map_items_in_place<F: FnMut(&mut Item)>(&mut self, f: F) {
+        self.items.iter_mut().for_each(f);
+    }
 
-        let expected = Some((95.462036, (2.0, 3.0)));
-        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
-        assert_eq!(actual, expected);
+    /// The item that `index` items were added to the tree before, the same number the
+    /// `_indexed` queries return, or None if it has been removed since or there is no such
+    /// item. The tree doesn't keep a map from these numbers to where items ended up, which
+    /// would take as much memory as the numbers themselves, so this looks through all of
+    /// them and takes time proportional to the number of items.
+    pub fn get(&self, index: usize) -> Option<&Item> {
+        let position = self.ids.iter().position(|&id| id == index)?;
+        Some(&self.items[position])
+    }
 
-        let expected = vec![(93.38094, (2.0, 3.0)), (95.707886, (0.0, 1.0))];
-        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
-        assert_eq!(actual, expected);
+    /// Consumes the tree and returns all the items in it, in no particular order.
+    pub fn into_items(self) -> Vec<Item> {
+        self.items
+    }
 
-        let mut tree = VPTree::new(|a: &(f32, f32), b| {
-            ((a.0 - b.0 as f32).powi(2) + (a.1 - b.1 as f32).powi(2)).sqrt()
-        });
-        tree.extend(points[0..1].to_vec());
+    /// Keeps only the items for which `predicate` returns true and rebuilds the tree out of
+    /// them. Rebuilding takes as long as `update`, no matter how few items are removed,
+    /// so removing many items at once is best done with a single call.
+    pub fn retain<F: FnMut(&Item) -> bool>(&mut self, predicate: F) {
+        let keep = self.items.iter().map(predicate).collect();
+        self.keep_only(keep);
+        self.update();
+    }
 
-        let expected = Some((95.462036, (2.0, 3.0)));
-        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
-        assert_eq!(actual, expected);
+    /// Keeps only the `capacity` items nearest to `focal` and rebuilds the tree out of them,
+    /// if it holds more than that, which makes for a cache of the items around a point that
+    /// moves. The items are chosen the way `find_k_nearest_neighbors` chooses them, so of
+    /// several equally far items the ones added first stay, see `VPTree`. Finding them takes
+    /// about as long as that query, and rebuilding as long as `update`.
+    pub fn prune_to_capacity(&mut self, focal: &Item, capacity: usize) {
+        if self.len() <= capacity {
+            return;
+        }
+        self.build();
+        let mut ctx = QueryContext::new();
+        self.k_nearest_neighbors(&mut ctx, &self.measure(focal), capacity, |_| true);
+        let mut keep = vec![false; self.items.len()];
+        for &(_, index) in ctx.nearest_neighbors.iter() {
+            keep[index] = true;
+        }
+        self.keep_only(keep);
+        self.update();
+    }
 
-        let expected = vec![(93.38094, (2.0, 3.0))];
-        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
-        assert_eq!(actual, expected);
+    /// Removes every item that is equal to another one in the tree, keeping just one of
+    /// them, and returns how many were removed. Many exactly equal items make for nodes that
+    /// can't tell them apart, so this can speed up queries as well. The tree is only rebuilt
+    /// if anything was removed.
+    pub fn dedup(&mut self) -> usize
+    where
+        Item: Hash + Eq,
+    {
+        let duplicate_count = self.remove_duplicates();
+        if duplicate_count > 0 {
+            self.update();
+        }
+        duplicate_count
+    }
 
-        let mut tree = VPTree::new(|a: &(f32, f32), b| {
-            ((a.0 - b.0 as f32).powi(2) + (a.1 - b.1 as f32).powi(2)).sqrt()
-        });
-        tree.extend(points[0..0].to_vec());
+    /* Removes the duplicates out of items, keeping the first of every set of equal items,
+    without rebuilding the tree */
+    fn remove_duplicates(&mut self) -> usize
+    where
+        Item: Hash + Eq,
+    {
+        let mut seen = HashSet::with_capacity(self.items.len());
+        let is_first = self.items.iter().map(|item| seen.insert(item)).collect();
+        drop(seen);
+        let len = self.items.len();
+        self.keep_only(is_first);
+        len - self.items.len()
+    }
 
-        let expected = None;
-        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
-        assert_eq!(actual, expected);
+    /* Removes the items at the positions keep is false for, along with their ids,
+    without rebuilding the tree */
+    fn keep_only(&mut self, keep: Vec<bool>) {
+        let mut keep_item = keep.iter();
+        self.items.retain(|_| *keep_item.next().unwrap());
+        let mut keep_id = keep.iter();
+        self.ids.retain(|_| *keep_id.next().unwrap());
+    }
 
-        let expected = vec![];
-        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
-        assert_eq!(actual, expected);
+    /// Removes all items, but keeps the memory allocated for them, so that refilling the
+    /// tree doesn't have to allocate it again.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.radii.clear();
+        self.subtree_lens.clear();
+        self.ids.clear();
+        self.next_id = 0;
+        self.leaf_size = 0;
+        self.decrementation_point = 0;
+        self.depth = 0;
+        self.is_updated = false;
+    }
+
+    /// Makes room for at least `additional` more items, so that adding them and building
+    /// the tree doesn't have to allocate memory again, same as `with_capacity`.
+    pub fn reserve(&mut self, additional: usize) {
+        self.items.reserve(additional);
+        self.ids.reserve(additional);
+        let nodes_len = leaves_len(depth(self.len() + additional, self.max_leaf_size)) - 1;
+        self.radii.reserve(nodes_len - self.radii.len());
+        self.subtree_lens
+            .reserve(nodes_len - self.subtree_lens.len());
+    }
+
+    /// Frees the memory allocated for more items than the tree holds, such as after `clear`.
+    pub fn shrink_to_fit(&mut self) {
+        self.items.shrink_to_fit();
+        self.radii.shrink_to_fit();
+        self.subtree_lens.shrink_to_fit();
+        self.ids.shrink_to_fit();
+    }
+
+    /// The number of items the tree can hold without allocating more memory for them.
+    pub fn capacity(&self) -> usize {
+        self.items.capacity()
+    }
+
+    /// An estimate of the bytes of memory the tree has allocated, counting the items, the
+    /// radii of the nodes and the numbers of the items, but not any memory the items or the
+    /// distances allocate themselves, such as the contents of a `Vec` item.
+    pub fn memory_footprint(&self) -> usize {
+        self.items.capacity() * size_of::<Item>()
+            + self.radii.capacity() * size_of::<Distance>()
+            + (self.subtree_lens.capacity() + self.ids.capacity()) * size_of::<usize>()
+    }
+
+    /* The items of the leaf at index, counting leaves only, which index is replaced with
+    the position of the first of them within leaves() */
+    fn get_leaf(&self, index: &mut usize) -> &[Item] {
+        debug_assert!(
+            *index < self.leaf_count(),
+            "leaf {} out of {}",
+            index,
+            self.leaf_count()
+        );
+        /* Leaves can have length leaf_size or leaf_size + 1.
+        All the big leaves have an index smaller than decrementation_point */
+        let range = if *index < self.decrementation_point {
+            *index *= self.leaf_size + 1;
+            *index..*index + self.leaf_size + 1
+        } else {
+            *index = (*index - self.decrementation_point) * self.leaf_size
+                + self.decrementation_point * (self.leaf_size + 1);
+            *index..*index + self.leaf_size
+        };
+        /* Otherwise leaf_size, decrementation_point and the number of nodes don't match,
+        and the leaf would take in pending items or the start of the next leaf */
+        debug_assert!(
+            range.end <= self.pending_start(),
+            "leaf ends at {}, past the start of the pending items at {}",
+            range.end,
+            self.pending_start()
+        );
+        &self.leaves()[range]
+    }
+
+    /* The index searches report for the item inner_index items into the leaf, or the pending
+    items, starting at leaf_start within leaves(), which get_item takes back */
+    fn leaf_item_index(&self, leaf_start: usize, inner_index: usize) -> usize {
+        let index = leaf_start + inner_index + self.radii.len();
+        debug_assert!(
+            index < self.items.len(),
+            "item {} out of {}",
+            index,
+            self.items.len()
+        );
+        index
+    }
+
+    /* What query results at the same distance are sorted by: the id of the item at index,
+    so that they come out in the order they were added however the tree is shaped, or in
+    deterministic trees its index, which doesn't depend on that order */
+    fn rank(&self, index: usize) -> usize {
+        if self.item_order.is_some() {
+            index
+        } else {
+            self.ids[index]
+        }
+    }
+
+    /* The order query results are sorted in, by distance and then by rank */
+    fn compare_neighbors(&self, a: &(Distance, usize), b: &(Distance, usize)) -> Ordering {
+        compare(&a.0, &b.0).then(self.rank(a.1).cmp(&self.rank(b.1)))
+    }
+
+    /* The same for farthest neighbors, which are sorted by descending distance */
+    fn compare_farthest_neighbors(&self, a: &(Distance, usize), b: &(Distance, usize)) -> Ordering {
+        compare(&b.0, &a.0).then(self.rank(a.1).cmp(&self.rank(b.1)))
+    }
+
+    /* The number of items in the subtree at index, vantage points included, which may be
+    a single leaf */
+    fn subtree_len(&self, index: usize) -> usize {
+        if let Some(len) = self.subtree_lens.get(index) {
+            return *len;
+        }
+        let leaf_index = index - self.radii.len();
+        debug_assert!(
+            leaf_index < self.leaf_count(),
+            "leaf {} out of {}",
+            leaf_index,
+            self.leaf_count()
+        );
+        if leaf_index < self.decrementation_point {
+            self.leaf_size + 1
+        } else {
+            self.leaf_size
+        }
+    }
+
+    /* The child at index, counting nodes and leaves together in the order they are laid out */
+    fn child(&self, index: usize) -> Child {
+        if index < self.radii.len() {
+            Child::Node(index)
+        } else {
+            Child::Leaf(index - self.radii.len())
+        }
+    }
+
+    fn get_item(&self, index: usize) -> &Item {
+        debug_assert!(
+            index < self.items.len(),
+            "item {} out of {}",
+            index,
+            self.items.len()
+        );
+        &self.items[index]
+    }
+
+    /* How far items are from needle, which is what searches take instead of the needle */
+    fn measure<'a>(&'a self, needle: &'a Item) -> impl Fn(&Item) -> Distance + 'a {
+        move |item| self.distance_calculator.distance(needle, item)
+    }
+
+    /* The traversal shared by all the queries, using unexplored as its stack. visit is called
+    with the index and the distance to the needle, as measure tells it, of every item the search
+    comes across and returns the new threshold, or None to stop the search right away. Subtrees
+    whose boundary is farther from the needle than the threshold are skipped. When inclusive is
+    set, subtrees whose boundary lies exactly at the threshold are explored as well, which is
+    what a search for items within the threshold (rather than closer than it) needs. */
+    fn search<
+        M: Fn(&Item) -> Distance,
+        U: SubtreeStack<Distance>,
+        F: FnMut(usize, Distance) -> Option<Distance>,
+    >(
+        &self,
+        measure: &M,
+        threshold: Distance,
+        inclusive: bool,
+        unexplored: &mut U,
+        visit: F,
+    ) {
+        self.search_counting(
+            measure,
+            threshold,
+            inclusive,
+            unexplored,
+            &|_| false,
+            &mut (),
+            visit,
+        );
+    }
+
+    /* Same as search, but tells counter about everything it looks at, and passes over the
+    leaf items skip returns true for without measuring them or visiting them. Vantage points
+    are never skipped, since their distances are what tells the search where to go. */
+    #[allow(clippy::too_many_arguments)]
+    fn search_counting<
+        M: Fn(&Item) -> Distance,
+        U: SubtreeStack<Distance>,
+        S: Fn(&Item) -> bool,
+        C: SearchCounter,
+        F: FnMut(usize, Distance) -> Option<Distance>,
+    >(
+        &self,
+        measure: &M,
+        mut threshold: Distance,
+        inclusive: bool,
+        unexplored: &mut U,
+        skip: &S,
+        counter: &mut C,
+        mut visit: F,
+    ) {
+        let mut child = self.child(0);
+        unexplored.clear();
+        // Items that aren't part of the tree yet, see pending_start
+        let pending_start = self.pending_start();
+        for (inner_index, item) in self.leaves()[pending_start..].iter().enumerate() {
+            if skip(item) {
+                continue;
+            }
+            counter.count_distance();
+            let distance = measure(item);
+            threshold = match visit(self.leaf_item_index(pending_start, inner_index), distance) {
+                Some(threshold) => threshold,
+                None => return,
+            };
+        }
+        loop {
+            let mut leaf_index = match child {
+                Child::Node(index) => {
+                    let radius = &self.radii[index];
+                    counter.count_node();
+                    counter.count_distance();
+                    let distance = measure(&self.items[index]);
+                    threshold = match visit(index, distance.clone()) {
+                        Some(threshold) => threshold,
+                        None => return,
+                    };
+                    child = if distance < *radius {
+                        /* Needle is within node's radius, therefore its nearest neigbors
+                        are likely to be within it too. The left tree, at index*2+1, contains
+                        all child nodes within node's radius, so search that tree and add
+                        the right tree - at index*2+2 - to the stack of unexplored nodes along
+                        with the distance between needle and current node's boundary.
+                        The boundary distance is always the larger distance minus the smaller one,
+                        so it can't underflow for unsigned distances. */
+                        unexplored.push((self.child(index * 2 + 2), radius.clone() - distance));
+                        self.child(index * 2 + 1)
+                    } else {
+                        unexplored.push((self.child(index * 2 + 1), distance - radius.clone()));
+                        self.child(index * 2 + 2)
+                    };
+                    continue;
+                }
+                Child::Leaf(leaf_index) => leaf_index,
+            };
+            counter.count_leaf();
+            for (inner_index, item) in self.get_leaf(&mut leaf_index).iter().enumerate() {
+                if skip(item) {
+                    continue;
+                }
+                counter.count_distance();
+                let distance = measure(item);
+                threshold = match visit(self.leaf_item_index(leaf_index, inner_index), distance) {
+                    Some(threshold) => threshold,
+                    None => return,
+                };
+            }
+            loop {
+                if let Some((potential_child, distance_to_boundary)) = unexplored.pop() {
+                    /* At this point it is guaranteed that the other child of potential_child's
+                    parent has been explored. Therefore, all the nodes on the other
+                    side of the parent's boundary (defined by its radius) have been considered.
+                    potential_child can possibly hold a viable neighbor candidate only if the
+                    threshold is so large, that it crosses over the boundary,
+                    meaning that there may be an item in potential_child that is closer
+                    to needle than threshold. */
+                    if threshold > distance_to_boundary
+                        || inclusive && threshold >= distance_to_boundary
+                    {
+                        child = potential_child;
+                        break;
+                    }
+                } else {
+                    return;
+                }
+            }
+        }
+    }
+
+    /* Same as search, except that instead of going back to the most recently skipped subtree
+    it always continues with the subtree whose boundary is closest to the needle, which tends
+    to tighten the threshold sooner. Every subtree inherits the distance to the boundary of
+    its parent, since it lies behind that boundary too. */
+    fn search_best_first<
+        M: Fn(&Item) -> Distance,
+        F: FnMut(usize, Distance) -> Option<Distance>,
+    >(
+        &self,
+        measure: &M,
+        mut threshold: Distance,
+        inclusive: bool,
+        mut visit: F,
+    ) {
+        let mut child = self.child(0);
+        let mut bound: Option<Distance> = None;
+        let mut unexplored = BinaryHeap::with_capacity(self.depth);
+        // Items that aren't part of the tree yet, see pending_start
+        let pending_start = self.pending_start();
+        for (inner_index, item) in self.leaves()[pending_start..].iter().enumerate() {
+            let distance = measure(item);
+            threshold = match visit(self.leaf_item_index(pending_start, inner_index), distance) {
+                Some(threshold) => threshold,
+                None => return,
+            };
+        }
+        loop {
+            let mut leaf_index = match child {
+                Child::Node(index) => {
+                    let radius = &self.radii[index];
+                    let distance = measure(&self.items[index]);
+                    threshold = match visit(index, distance.clone()) {
+                        Some(threshold) => threshold,
+                        None => return,
+                    };
+                    let (near_index, far_index, distance_to_boundary) = if distance < *radius {
+                        (index * 2 + 1, index * 2 + 2, radius.clone() - distance)
+                    } else {
+                        (index * 2 + 2, index * 2 + 1, distance - radius.clone())
+                    };
+                    unexplored.push(Unexplored {
+                        child: self.child(far_index),
+                        distance_to_boundary: match &bound {
+                            Some(bound) if bound > &distance_to_boundary => bound.clone(),
+                            _ => distance_to_boundary,
+                        },
+                    });
+                    child = self.child(near_index);
+                    continue;
+                }
+                Child::Leaf(leaf_index) => leaf_index,
+            };
+            for (inner_index, item) in self.get_leaf(&mut leaf_index).iter().enumerate() {
+                let distance = measure(item);
+                threshold = match visit(self.leaf_item_index(leaf_index, inner_index), distance) {
+                    Some(threshold) => threshold,
+                    None => return,
+                };
+            }
+            match unexplored.pop() {
+                /* Every other unexplored subtree is at least as far away as this one,
+                so once it can't contain anything of interest, neither can the rest. */
+                Some(Unexplored {
+                    child: potential_child,
+                    distance_to_boundary,
+                }) if threshold > distance_to_boundary
+                    || inclusive && threshold >= distance_to_boundary =>
+                {
+                    child = potential_child;
+                    bound = Some(distance_to_boundary);
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /* Items that accept rejects are passed over without affecting the threshold */
+    fn nearest_neighbor<M: Fn(&Item) -> Distance, C: SearchCounter, F: Fn(&Item) -> bool>(
+        &self,
+        ctx: &mut QueryContext<Distance>,
+        measure: &M,
+        max_visits: usize,
+        counter: &mut C,
+        accept: F,
+    ) -> Option<(Distance, usize)> {
+        if max_visits == 0 {
+            return None;
+        }
+        let mut nearest_neighbor = 0;
+        let mut threshold = Distance::max_value();
+        let mut visits = 0;
+        /* Of several items at the same distance the one ranked first is nearest, as in
+        consider_item */
+        self.search_counting(
+            measure,
+            threshold.clone(),
+            true,
+            &mut ctx.unexplored,
+            &|_| false,
+            counter,
+            |index, distance| {
+                let nearer = distance < threshold
+                    || distance == threshold && self.rank(index) < self.rank(nearest_neighbor);
+                if nearer && accept(self.get_item(index)) {
+                    nearest_neighbor = index;
+                    threshold = distance;
+                }
+                visits += 1;
+                if visits < max_visits {
+                    Some(threshold.clone())
+                } else {
+                    None
+                }
+            },
+        );
+        if threshold < Distance::max_value() {
+            Some((threshold, nearest_neighbor))
+        } else {
+            None
+        }
+    }
+
+    pub fn find_nearest_neighbor(&mut self, needle: &Item) -> Option<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.find_nearest_neighbor_with(&mut QueryContext::new(), needle)
+    }
+
+    /// Finds the item nearest to the needle among those strictly closer than `max_distance`,
+    /// or None if there is no such item. Knowing how far the nearest neighbor can be at most,
+    /// say from an earlier query, lets the search skip more of the tree from the start.
+    pub fn find_nearest_neighbor_within(
+        &mut self,
+        needle: &Item,
+        max_distance: Distance,
+    ) -> Option<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut nearest_neighbor: Option<(Distance, usize)> = None;
+        let mut threshold = max_distance;
+        /* Of several items at the same distance the one ranked first is nearest, as in
+        consider_item */
+        self.search(
+            &self.measure(needle),
+            threshold.clone(),
+            true,
+            &mut Vec::new(),
+            |index, distance| {
+                let nearer = match &nearest_neighbor {
+                    Some((_, nearest)) => {
+                        distance < threshold
+                            || distance == threshold && self.rank(index) < self.rank(*nearest)
+                    }
+                    None => distance < threshold,
+                };
+                if nearer {
+                    threshold = distance.clone();
+                    nearest_neighbor = Some((distance, index));
+                }
+                Some(threshold.clone())
+            },
+        );
+        nearest_neighbor.map(|(distance, index)| (distance, self.get_item(index).clone()))
+    }
+
+    /// Same as `find_nearest_neighbor`, but uses the scratch space in `ctx`.
+    pub fn find_nearest_neighbor_with(
+        &mut self,
+        ctx: &mut QueryContext<Distance>,
+        needle: &Item,
+    ) -> Option<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        self.nearest_neighbor(ctx, &self.measure(needle), usize::MAX, &mut (), |_| true)
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+    }
+
+    /// Same as `find_nearest_neighbor`, but only returns the distance to the nearest
+    /// neighbor, so the item isn't copied and doesn't have to be `Clone`.
+    pub fn nearest_neighbor_distance(&mut self, needle: &Item) -> Option<Distance> {
+        self.build();
+        self.nearest_neighbor(
+            &mut QueryContext::new(),
+            &self.measure(needle),
+            usize::MAX,
+            &mut (),
+            |_| true,
+        )
+        .map(|(distance, _)| distance)
+    }
+
+    /// Finds the nearest neighbor of every needle, reusing the same scratch space for all
+    /// of them.
+    pub fn find_nearest_neighbors_batch(
+        &mut self,
+        needles: &[Item],
+    ) -> Vec<Option<(Distance, Item)>>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut ctx = QueryContext::new();
+        needles
+            .iter()
+            .map(|needle| {
+                self.nearest_neighbor(&mut ctx, &self.measure(needle), usize::MAX, &mut (), |_| {
+                    true
+                })
+                .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            })
+            .collect()
+    }
+
+    /// Same as `find_nearest_neighbors_batch`, but searches for the needles in parallel,
+    /// with separate scratch space for every thread. This only borrows the tree, so it can
+    /// be shared with other threads meanwhile, and doesn't build it: items added since it
+    /// was last built are compared to every needle one by one, so call `build` first after
+    /// adding many of them.
+    #[cfg(feature = "rayon")]
+    pub fn par_find_nearest_neighbors_batch(
+        &self,
+        needles: &[Item],
+    ) -> Vec<Option<(Distance, Item)>>
+    where
+        Item: Clone + Send + Sync,
+        Distance: Send + Sync,
+        DistanceCalculator: Sync,
+    {
+        needles
+            .par_iter()
+            .map_init(QueryContext::new, |ctx, needle| {
+                self.nearest_neighbor(ctx, &self.measure(needle), usize::MAX, &mut (), |_| true)
+                    .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            })
+            .collect()
+    }
+
+    /// Same as `find_nearest_neighbor`, but also counts how much work the search took.
+    pub fn find_nearest_neighbor_instrumented(
+        &mut self,
+        needle: &Item,
+    ) -> (Option<(Distance, Item)>, SearchStats)
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut stats = SearchStats::default();
+        let nearest_neighbor = self
+            .nearest_neighbor(
+                &mut QueryContext::new(),
+                &self.measure(needle),
+                usize::MAX,
+                &mut stats,
+                |_| true,
+            )
+            .map(|(distance, index)| (distance, self.get_item(index).clone()));
+        (nearest_neighbor, stats)
+    }
+
+    /// Like `find_nearest_neighbor`, but gives up once `max_visits` distances have been
+    /// computed, returning the nearest neighbor found so far. Both the nodes visited on the
+    /// way down and the leaf items compared count towards the budget.
+    pub fn find_nearest_neighbor_budgeted(
+        &mut self,
+        needle: &Item,
+        max_visits: usize,
+    ) -> Option<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        self.nearest_neighbor(
+            &mut QueryContext::new(),
+            &self.measure(needle),
+            max_visits,
+            &mut (),
+            |_| true,
+        )
+        .map(|(distance, index)| (distance, self.get_item(index).clone()))
+    }
+
+    /// Finds the nearest item that isn't equal to the needle, which is useful when the
+    /// needle is itself in the tree.
+    pub fn find_nearest_neighbor_excluding(&mut self, needle: &Item) -> Option<(Distance, Item)>
+    where
+        Item: Clone + PartialEq,
+    {
+        self.build();
+        self.nearest_neighbor(
+            &mut QueryContext::new(),
+            &self.measure(needle),
+            usize::MAX,
+            &mut (),
+            |item| item != needle,
+        )
+        .map(|(distance, index)| (distance, self.get_item(index).clone()))
+    }
+
+    /* Leaves the k nearest neighbors of needle in ctx.nearest_neighbors, sorted by distance,
+    out of the items at the indices accept returns true for */
+    fn k_nearest_neighbors<M: Fn(&Item) -> Distance, F: Fn(usize) -> bool>(
+        &self,
+        ctx: &mut QueryContext<Distance>,
+        measure: &M,
+        k: usize,
+        accept: F,
+    ) {
+        let QueryContext {
+            unexplored,
+            candidates,
+            nearest_neighbors,
+        } = ctx;
+        candidates.clear();
+        nearest_neighbors.clear();
+        /* consider_item always keeps at least one item */
+        if k == 0 {
+            return;
+        }
+        let mut threshold = Distance::max_value();
+        self.search(
+            measure,
+            threshold.clone(),
+            true,
+            unexplored,
+            |index, distance| {
+                if distance <= threshold && accept(index) {
+                    threshold =
+                        consider_item(Nearest(distance, self.rank(index), index), k, candidates);
+                }
+                Some(threshold.clone())
+            },
+        );
+        sort_neighbors(candidates, nearest_neighbors);
+    }
+
+    /// Finds the k items nearest to the needle, sorted by distance.
+    /// If the tree holds fewer than k items, all of them are returned.
+    pub fn find_k_nearest_neighbors(&mut self, needle: &Item, k: usize) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.find_k_nearest_neighbors_with(&mut QueryContext::new(), needle, k)
+    }
+
+    /// Same as `find_k_nearest_neighbors`, but returns references to the items instead of
+    /// copies of them, so that only the ones worth keeping need to be cloned, and items don't
+    /// have to be `Clone` at all. The tree stays borrowed for as long as the references live.
+    pub fn find_k_nearest_neighbors_ref(
+        &mut self,
+        needle: &Item,
+        k: usize,
+    ) -> Vec<(Distance, &Item)> {
+        self.build();
+        let tree = &*self;
+        let mut ctx = QueryContext::new();
+        tree.k_nearest_neighbors(&mut ctx, &tree.measure(needle), k, |_| true);
+        ctx.nearest_neighbors
+            .into_iter()
+            .map(move |(distance, index)| (distance, tree.get_item(index)))
+            .collect()
+    }
+
+    /// Same as `find_k_nearest_neighbors`, but uses the scratch space in `ctx`.
+    pub fn find_k_nearest_neighbors_with(
+        &mut self,
+        ctx: &mut QueryContext<Distance>,
+        needle: &Item,
+        k: usize,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        let mut nearest_neighbors = Vec::with_capacity(min(k, self.len()));
+        self.find_k_nearest_neighbors_into_with(ctx, needle, k, &mut nearest_neighbors);
+        nearest_neighbors
+    }
+
+    /// Same as `find_k_nearest_neighbors`, but writes the results into `out`, replacing its
+    /// previous contents. Reusing one buffer across many queries saves allocating the result.
+    pub fn find_k_nearest_neighbors_into(
+        &mut self,
+        needle: &Item,
+        k: usize,
+        out: &mut Vec<(Distance, Item)>,
+    ) where
+        Item: Clone,
+    {
+        self.find_k_nearest_neighbors_into_with(&mut QueryContext::new(), needle, k, out)
+    }
+
+    /// Combines `find_k_nearest_neighbors_into` and `find_k_nearest_neighbors_with`,
+    /// so that the query doesn't allocate at all once the buffers have grown large enough.
+    pub fn find_k_nearest_neighbors_into_with(
+        &mut self,
+        ctx: &mut QueryContext<Distance>,
+        needle: &Item,
+        k: usize,
+        out: &mut Vec<(Distance, Item)>,
+    ) where
+        Item: Clone,
+    {
+        self.build();
+        self.k_nearest_neighbors(ctx, &self.measure(needle), k, |_| true);
+        out.clear();
+        out.extend(
+            ctx.nearest_neighbors
+                .iter()
+                .map(|(distance, index)| (distance.clone(), self.get_item(*index).clone())),
+        );
+    }
+
+    /// Same as `find_k_nearest_neighbors` with k = N, but returns the neighbors in an array
+    /// instead of allocating a `Vec` for them, which is worth it for a small N known up
+    /// front. If the tree holds fewer than N items, the array ends with as many `None`s.
+    /// Unless the tree has to be built first, this doesn't allocate at all: the search keeps
+    /// the subtrees it has yet to explore on the stack too.
+    pub fn find_nearest_n<const N: usize>(&mut self, needle: &Item) -> [Option<(Distance, Item)>; N]
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut nearest: [Option<(Distance, usize)>; N] = std::array::from_fn(|_| None);
+        if N > 0 {
+            let mut threshold = Distance::max_value();
+            self.search(
+                &self.measure(needle),
+                threshold.clone(),
+                true,
+                &mut ArrayStack::new(),
+                |index, distance| {
+                    /* Same as consider_item, the farthest neighbor falls off */
+                    if distance <= threshold && distance < Distance::max_value() {
+                        let neighbor = (distance.clone(), index);
+                        let position = nearest
+                            .iter()
+                            .position(|other| match other {
+                                Some(other) => {
+                                    self.compare_neighbors(&neighbor, other) == Ordering::Less
+                                }
+                                None => true,
+                            })
+                            .unwrap_or(N);
+                        if position < N {
+                            nearest[position..].rotate_right(1);
+                            nearest[position] = Some((distance, index));
+                            if let Some((farthest, _)) = &nearest[N - 1] {
+                                threshold = farthest.clone();
+                            }
+                        }
+                    }
+                    Some(threshold.clone())
+                },
+            );
+        }
+        nearest.map(|neighbor| {
+            neighbor.map(|(distance, index)| (distance, self.get_item(index).clone()))
+        })
+    }
+
+    /// Returns an iterator over the items sorted by distance to the needle, in the same order
+    /// as `find_k_nearest_neighbors`. The search only goes as far as needed to find the next
+    /// item, so there is no need to know up front how many of them will be needed.
+    pub fn nearest_neighbors_iter<'a>(
+        &'a mut self,
+        needle: &'a Item,
+    ) -> NearestNeighbors<'a, Item, Distance, DistanceCalculator> {
+        self.build();
+        let tree = &*self;
+        let mut candidates = BinaryHeap::new();
+        // Items that aren't part of the tree yet, see pending_start
+        let pending_start = tree.pending_start();
+        for (inner_index, item) in tree.leaves()[pending_start..].iter().enumerate() {
+            let distance = tree.distance_calculator.distance(needle, item);
+            if is_comparable(&distance) {
+                let index = tree.leaf_item_index(pending_start, inner_index);
+                candidates.push(Candidate {
+                    distance,
+                    is_item: true,
+                    rank: tree.rank(index),
+                    index,
+                });
+            }
+        }
+        candidates.push(Candidate {
+            distance: Distance::min_value(),
+            is_item: false,
+            rank: 0,
+            index: 0,
+        });
+        NearestNeighbors {
+            tree,
+            needle,
+            candidates,
+        }
+    }
+
+    /// Same results as `find_k_nearest_neighbors`, but explores the subtree closest to
+    /// the needle first instead of backtracking depth-first. This usually takes fewer
+    /// distance computations, at the cost of maintaining a priority queue.
+    pub fn find_k_nearest_neighbors_best_first(
+        &mut self,
+        needle: &Item,
+        k: usize,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        if k == 0 {
+            return Vec::new();
+        }
+        let mut nearest_neighbors = BinaryHeap::with_capacity(min(k, self.len()));
+        let mut threshold = Distance::max_value();
+        self.search_best_first(
+            &self.measure(needle),
+            threshold.clone(),
+            true,
+            |index, distance| {
+                if distance <= threshold {
+                    threshold = consider_item(
+                        Nearest(distance, self.rank(index), index),
+                        k,
+                        &mut nearest_neighbors,
+                    );
+                }
+                Some(threshold.clone())
+            },
+        );
+        nearest_neighbors
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Nearest(distance, _, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Finds all the items within `threshold` of the needle, sorted by distance. Items
+    /// exactly `threshold` away are included, so with a threshold of `Distance::zero()`
+    /// this finds every item at distance zero, for integer and floating point distances alike.
+    pub fn find_neighbors_within_radius(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        let mut neighbors = Vec::new();
+        self.find_neighbors_within_radius_into(needle, threshold, true, &mut neighbors);
+        neighbors
+    }
+
+    /// Same as `find_nearest_neighbor`, but only considers items for which `predicate`
+    /// returns true. The whole tree is still searched, so no matching item gets missed
+    /// because of items that don't match.
+    pub fn find_nearest_neighbor_matching<F: Fn(&Item) -> bool>(
+        &mut self,
+        needle: &Item,
+        predicate: F,
+    ) -> Option<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        self.nearest_neighbor(
+            &mut QueryContext::new(),
+            &self.measure(needle),
+            usize::MAX,
+            &mut (),
+            predicate,
+        )
+        .map(|(distance, index)| (distance, self.get_item(index).clone()))
+    }
+
+    /// Same as `find_k_nearest_neighbors`, but only considers items for which `predicate`
+    /// returns true.
+    pub fn find_k_nearest_neighbors_matching<F: Fn(&Item) -> bool>(
+        &mut self,
+        needle: &Item,
+        k: usize,
+        predicate: F,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut ctx = QueryContext::new();
+        self.k_nearest_neighbors(&mut ctx, &self.measure(needle), k, |index| {
+            predicate(self.get_item(index))
+        });
+        ctx.nearest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Finds the k nearest items that all have a different key, as `key` tells it, sorted by
+    /// distance: of all the items with the same key, only the nearest one can be among them.
+    /// The search is pruned by the distance of the k-th nearest distinct key found so far,
+    /// so the rest of the tree is skipped just like `find_k_nearest_neighbors` skips it.
+    /// Of items at the same distance, the one added first wins, both among the items of a key
+    /// and for the last of the k places.
+    pub fn find_k_nearest_distinct_by<K: Eq, F: Fn(&Item) -> K>(
+        &mut self,
+        needle: &Item,
+        k: usize,
+        key: F,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        if k == 0 {
+            return Vec::new();
+        }
+        /* The nearest item of every key among the k nearest keys found so far, sorted.
+        A key pushed out of them can come back with a nearer item later, since all of its
+        items that were already found are farther than the new k-th nearest key. */
+        let mut nearest: Vec<(Distance, usize, K)> = Vec::with_capacity(k + 1);
+        let mut threshold = Distance::max_value();
+        self.search(
+            &self.measure(needle),
+            threshold.clone(),
+            true,
+            &mut Vec::new(),
+            |index, distance| {
+                /* How a kept item compares to this one, by distance and then by rank, like
+                the results of every other query */
+                let rank = self.rank(index);
+                let order = |(nearer, nearer_index, _): &(Distance, usize, K)| {
+                    compare(nearer, &distance).then(self.rank(*nearer_index).cmp(&rank))
+                };
+                if distance > threshold
+                    || (nearest.len() == k && order(&nearest[k - 1]) != Ordering::Greater)
+                {
+                    return Some(threshold.clone());
+                }
+                let item_key = key(self.get_item(index));
+                if let Some(same) = nearest.iter().position(|(_, _, key)| *key == item_key) {
+                    if order(&nearest[same]) != Ordering::Greater {
+                        return Some(threshold.clone());
+                    }
+                    nearest.remove(same);
+                }
+                let position = nearest.partition_point(|kept| order(kept) == Ordering::Less);
+                nearest.insert(position, (distance, index, item_key));
+                nearest.truncate(k);
+                if nearest.len() == k {
+                    threshold = nearest[k - 1].0.clone();
+                }
+                Some(threshold.clone())
+            },
+        );
+        nearest
+            .into_iter()
+            .map(|(distance, index, _)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Same as `find_k_nearest_neighbors_matching`, for a predicate that is cheaper than
+    /// measuring a distance, such as checking whether a point lies in a box. `in_region` is
+    /// called on every leaf item before its distance to the needle is measured, and items
+    /// outside the region are never measured at all. Vantage points are always measured,
+    /// since the search needs their distances to find its way, and `in_region` is only
+    /// called on them after that.
+    pub fn find_k_nearest_in_region<R: Fn(&Item) -> bool>(
+        &mut self,
+        needle: &Item,
+        k: usize,
+        in_region: R,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        /* consider_item always keeps at least one item */
+        if k == 0 {
+            return Vec::new();
+        }
+        let mut candidates = BinaryHeap::with_capacity(min(k, self.len()));
+        let mut threshold = Distance::max_value();
+        self.search_counting(
+            &self.measure(needle),
+            threshold.clone(),
+            true,
+            &mut Vec::new(),
+            &|item| !in_region(item),
+            &mut (),
+            |index, distance| {
+                /* Leaf items outside the region have been skipped already */
+                if distance <= threshold
+                    && (index >= self.radii.len() || in_region(self.get_item(index)))
+                {
+                    threshold = consider_item(
+                        Nearest(distance, self.rank(index), index),
+                        k,
+                        &mut candidates,
+                    );
+                }
+                Some(threshold.clone())
+            },
+        );
+        candidates
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Nearest(distance, _, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /* The indices of the items within threshold of needle that accept accepts, in the
+    order the search comes across them */
+    fn neighbors_within_radius<M: Fn(&Item) -> Distance, F: Fn(&Item) -> bool>(
+        &self,
+        measure: &M,
+        threshold: Distance,
+        inclusive: bool,
+        accept: F,
+    ) -> Vec<(Distance, usize)> {
+        let mut nearest_neighbors = Vec::new();
+        /* We're only interested in nodes than lie within threshold distance to the needle,
+        so the threshold never changes. When items exactly at it are included, subtrees whose
+        boundary lies exactly at it still have to be searched, otherwise nothing in them can
+        be close enough. */
+        self.search(
+            measure,
+            threshold.clone(),
+            inclusive,
+            &mut Vec::new(),
+            |index, distance| {
+                let within = if inclusive {
+                    distance <= threshold
+                } else {
+                    distance < threshold
+                };
+                if within && accept(self.get_item(index)) {
+                    nearest_neighbors.push((distance, index));
+                }
+                Some(threshold.clone())
+            },
+        );
+        nearest_neighbors
+    }
+
+    /// Same as `find_neighbors_within_radius`, but only returns items for which `predicate`
+    /// returns true.
+    pub fn find_neighbors_within_radius_matching<F: Fn(&Item) -> bool>(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+        predicate: F,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut nearest_neighbors =
+            self.neighbors_within_radius(&self.measure(needle), threshold, true, predicate);
+        nearest_neighbors.sort_by(|a, b| self.compare_neighbors(a, b));
+        nearest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Same as `find_neighbors_within_radius`, but leaves out items exactly `threshold` away,
+    /// so that the results of two queries whose balls only touch never share an item.
+    /// Subtrees that lie exactly `threshold` away are skipped, since none of their items
+    /// could be returned.
+    pub fn find_neighbors_within_radius_exclusive(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut nearest_neighbors =
+            self.neighbors_within_radius(&self.measure(needle), threshold, false, |_| true);
+        nearest_neighbors.sort_by(|a, b| self.compare_neighbors(a, b));
+        nearest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Finds the k items that `score` rates highest among those within `candidate_radius`
+    /// of the needle, sorted by descending score, for re-ranking the items near the needle by
+    /// something other than their distance to it. `score` is only called on the items within
+    /// the radius, so it can be expensive. Items scoring NaN are left out. Items with the
+    /// same score are sorted by distance, as `find_neighbors_within_radius` sorts them.
+    pub fn find_k_best_near<S: Fn(&Item) -> f64>(
+        &mut self,
+        needle: &Item,
+        candidate_radius: Distance,
+        k: usize,
+        score: S,
+    ) -> Vec<(f64, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut candidates =
+            self.neighbors_within_radius(&self.measure(needle), candidate_radius, true, |_| true);
+        candidates.sort_by(|a, b| self.compare_neighbors(a, b));
+        let mut best: Vec<(f64, usize)> = candidates
+            .into_iter()
+            .map(|(_, index)| (score(self.get_item(index)), index))
+            .filter(|(score, _)| !score.is_nan())
+            .collect();
+        /* The sort is stable, so equal scores stay sorted by distance */
+        best.sort_by(|a, b| compare(&b.0, &a.0));
+        best.truncate(k);
+        best.into_iter()
+            .map(|(score, index)| (score, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Same as `find_neighbors_within_radius`, but returns the items in whatever order the
+    /// search comes across them, which saves sorting them.
+    pub fn find_neighbors_within_radius_unsorted(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        self.neighbors_within_radius(&self.measure(needle), threshold, true, |_| true)
+            .into_iter()
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Same as `find_neighbors_within_radius`, but sorts the items with `order` instead of
+    /// by distance, such as by a timestamp or priority they carry, which saves collecting them
+    /// unsorted and sorting them again. Items that `order` finds equal come in whatever
+    /// order the search came across them.
+    pub fn find_neighbors_within_radius_sorted_by<F>(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+        order: F,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+        F: FnMut(&(Distance, Item), &(Distance, Item)) -> Ordering,
+    {
+        let mut neighbors = self.find_neighbors_within_radius_unsorted(needle, threshold);
+        neighbors.sort_by(order);
+        neighbors
+    }
+
+    /// Same as `find_neighbors_within_radius`, but writes the results into `out`, replacing its
+    /// previous contents, and only sorts them if `sorted` is set. Reusing one buffer across
+    /// many queries saves allocating the result.
+    pub fn find_neighbors_within_radius_into(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+        sorted: bool,
+        out: &mut Vec<(Distance, Item)>,
+    ) where
+        Item: Clone,
+    {
+        self.build();
+        let mut neighbors =
+            self.neighbors_within_radius(&self.measure(needle), threshold, true, |_| true);
+        if sorted {
+            neighbors.sort_by(|a, b| self.compare_neighbors(a, b));
+        }
+        out.clear();
+        out.extend(
+            neighbors
+                .into_iter()
+                .map(|(distance, index)| (distance, self.get_item(index).clone())),
+        );
+    }
+
+    /// Same as `find_nearest_neighbor`, but for a needle that doesn't have to be an `Item`,
+    /// such as just the coordinates of items that hold more than that. `distance_to_needle`
+    /// tells how far an item is from the needle, and has to agree with the distance calculator
+    /// of the tree, as if the needle were an item: otherwise the search can miss neighbors.
+    pub fn find_nearest_neighbor_by<M: Fn(&Item) -> Distance>(
+        &mut self,
+        distance_to_needle: M,
+    ) -> Option<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        self.nearest_neighbor(
+            &mut QueryContext::new(),
+            &distance_to_needle,
+            usize::MAX,
+            &mut (),
+            |_| true,
+        )
+        .map(|(distance, index)| (distance, self.get_item(index).clone()))
+    }
+
+    /// Same as `find_k_nearest_neighbors`, but for a needle that doesn't have to be an
+    /// `Item`, see `find_nearest_neighbor_by`.
+    pub fn find_k_nearest_neighbors_by<M: Fn(&Item) -> Distance>(
+        &mut self,
+        k: usize,
+        distance_to_needle: M,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut ctx = QueryContext::new();
+        self.k_nearest_neighbors(&mut ctx, &distance_to_needle, k, |_| true);
+        ctx.nearest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Same as `find_neighbors_within_radius`, but for a needle that doesn't have to be an
+    /// `Item`, see `find_nearest_neighbor_by`.
+    pub fn find_neighbors_within_radius_by<M: Fn(&Item) -> Distance>(
+        &mut self,
+        threshold: Distance,
+        distance_to_needle: M,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut nearest_neighbors =
+            self.neighbors_within_radius(&distance_to_needle, threshold, true, |_| true);
+        nearest_neighbors.sort_by(|a, b| self.compare_neighbors(a, b));
+        nearest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Same as `find_nearest_neighbor`, but returns the number of items that were added to
+    /// the tree before the nearest neighbor instead of the item itself. For a tree made with
+    /// `build_from` or `extend`, that is its position among the items it was given, which
+    /// stays the same through rebuilds and removals, until `clear`.
+    pub fn find_nearest_neighbor_indexed(&mut self, needle: &Item) -> Option<(Distance, usize)> {
+        self.build();
+        self.nearest_neighbor(
+            &mut QueryContext::new(),
+            &self.measure(needle),
+            usize::MAX,
+            &mut (),
+            |_| true,
+        )
+        .map(|(distance, index)| (distance, self.ids[index]))
+    }
+
+    /// Same as `find_k_nearest_neighbors`, but returns the numbers of the items,
+    /// see `find_nearest_neighbor_indexed`.
+    pub fn find_k_nearest_neighbors_indexed(
+        &mut self,
+        needle: &Item,
+        k: usize,
+    ) -> Vec<(Distance, usize)> {
+        self.build();
+        let mut ctx = QueryContext::new();
+        self.k_nearest_neighbors(&mut ctx, &self.measure(needle), k, |_| true);
+        ctx.nearest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.ids[index]))
+            .collect()
+    }
+
+    /// The k nearest neighbors of every item in the tree, leaving out the item itself but
+    /// not items equal to it, as a k-NN graph. The list at position `i` belongs to the item
+    /// `i` items were added to the tree before, and holds the numbers of its neighbors with
+    /// their distances, sorted by distance, so the graph keeps referring to the same items
+    /// however the tree is rebuilt. Items that have been removed get empty lists.
+    /// This takes about `len()` times as long as `find_k_nearest_neighbors`.
+    pub fn knn_graph(&mut self, k: usize) -> Vec<Vec<(Distance, usize)>> {
+        self.build();
+        let mut graph: Vec<Vec<(Distance, usize)>> =
+            (0..self.next_id).map(|_| Vec::new()).collect();
+        let mut ctx = QueryContext::new();
+        for (position, (item, &id)) in self.items.iter().zip(self.ids.iter()).enumerate() {
+            /* Equal items are told apart by where they are stored */
+            self.k_nearest_neighbors(&mut ctx, &self.measure(item), k, |index| index != position);
+            graph[id] = ctx
+                .nearest_neighbors
+                .drain(..)
+                .map(|(distance, index)| (distance, self.ids[index]))
+                .collect();
+        }
+        graph
+    }
+
+    /// Same as `find_neighbors_within_radius`, but returns the numbers of the items,
+    /// see `find_nearest_neighbor_indexed`.
+    pub fn find_neighbors_within_radius_indexed(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+    ) -> Vec<(Distance, usize)> {
+        self.build();
+        let mut nearest_neighbors =
+            self.neighbors_within_radius(&self.measure(needle), threshold, true, |_| true);
+        nearest_neighbors.sort_by(|a, b| self.compare_neighbors(a, b));
+        nearest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.ids[index]))
+            .collect()
+    }
+
+    /// Counts the items within `threshold` of the needle, without collecting or sorting them.
+    pub fn count_within_radius(&mut self, needle: &Item, threshold: Distance) -> usize {
+        self.build();
+        let mut count = 0;
+        self.search(
+            &self.measure(needle),
+            threshold.clone(),
+            true,
+            &mut Vec::new(),
+            |_, distance| {
+                if distance <= threshold {
+                    count += 1;
+                }
+                Some(threshold.clone())
+            },
+        );
+        count
+    }
+
+    /// Counts the items within each of `thresholds` of the needle, in a single search as
+    /// wide as the largest of them. `thresholds` has to be sorted in ascending order.
+    pub fn count_within_radii(&mut self, needle: &Item, thresholds: &[Distance]) -> Vec<usize> {
+        assert!(
+            thresholds
+                .windows(2)
+                .all(|pair| compare(&pair[0], &pair[1]) != Ordering::Greater),
+            "thresholds are not sorted in ascending order"
+        );
+        self.build();
+        let mut counts = vec![0; thresholds.len()];
+        let largest = match thresholds.last() {
+            Some(largest) => largest.clone(),
+            None => return counts,
+        };
+        /* Every item is only counted for the smallest threshold it is within,
+        the counts for the larger ones are added up afterwards */
+        self.search(
+            &self.measure(needle),
+            largest.clone(),
+            true,
+            &mut Vec::new(),
+            |_, distance| {
+                if distance <= largest {
+                    counts[thresholds.partition_point(|threshold| *threshold < distance)] += 1;
+                }
+                Some(largest.clone())
+            },
+        );
+        for i in 1..counts.len() {
+            counts[i] += counts[i - 1];
+        }
+        counts
+    }
+
+    /// Finds the items less than `ring_width * ring_count` away from the needle, grouped into
+    /// `ring_count` concentric rings in a single search. Ring `i` holds the items at least
+    /// `i * ring_width` and less than `(i + 1) * ring_width` away, sorted by distance, so an
+    /// item exactly on the boundary between two rings belongs to the outer one, and items
+    /// exactly `ring_width * ring_count` away are left out.
+    pub fn neighbors_by_ring(
+        &mut self,
+        needle: &Item,
+        ring_width: Distance,
+        ring_count: usize,
+    ) -> Vec<Vec<Item>>
+    where
+        Item: Clone,
+        Distance: NumCast + Mul<Output = Distance> + Div<Output = Distance>,
+    {
+        self.build();
+        if ring_count == 0 {
+            return Vec::new();
+        }
+        let mut rings: Vec<Vec<(Distance, usize)>> = vec![Vec::new(); ring_count];
+        let outer = ring_width.clone()
+            * Distance::from(ring_count).expect("ring_count doesn't fit in Distance");
+        self.search(
+            &self.measure(needle),
+            outer.clone(),
+            false,
+            &mut Vec::new(),
+            |index, distance| {
+                if distance < outer {
+                    /* Rounding can put items just inside the outermost ring past it */
+                    if let Some(ring) = (distance.clone() / ring_width.clone()).to_usize() {
+                        rings[ring.min(ring_count - 1)].push((distance, index));
+                    }
+                }
+                Some(outer.clone())
+            },
+        );
+        rings
+            .into_iter()
+            .map(|mut ring| {
+                ring.sort_by(|a, b| self.compare_neighbors(a, b));
+                ring.into_iter()
+                    .map(|(_, index)| self.get_item(index).clone())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Same as `count_within_radius`, but counts the items near a vantage point all at once,
+    /// without measuring them, if the triangle inequality puts all of them within `threshold`
+    /// of the needle. This makes counting many items much faster. The count is exact for
+    /// integer distances, but with floating point ones, rounding can make it differ by the
+    /// items whose distances lie right at `threshold`.
+    pub fn count_within_radius_approx(&mut self, needle: &Item, threshold: Distance) -> usize {
+        self.build();
+        let measure = self.measure(needle);
+        let mut count = self.leaves()[self.pending_start()..]
+            .iter()
+            .filter(|item| measure(item) <= threshold)
+            .count();
+        let mut unexplored = vec![self.child(0)];
+        while let Some(child) = unexplored.pop() {
+            let index = match child {
+                Child::Node(index) => index,
+                Child::Leaf(mut leaf_index) => {
+                    count += self
+                        .get_leaf(&mut leaf_index)
+                        .iter()
+                        .filter(|item| measure(item) <= threshold)
+                        .count();
+                    continue;
+                }
+            };
+            let radius = &self.radii[index];
+            let distance = measure(&self.items[index]);
+            if distance <= threshold {
+                count += 1;
+                /* Every item on the left is within radius of the vantage point, so it is
+                at most distance + radius away from the needle */
+                if *radius <= threshold.clone() - distance.clone() {
+                    count += self.subtree_len(index * 2 + 1);
+                    unexplored.push(self.child(index * 2 + 2));
+                    continue;
+                }
+            }
+            /* Same pruning as search, inclusive of the boundary */
+            if distance < *radius {
+                if threshold >= radius.clone() - distance {
+                    unexplored.push(self.child(index * 2 + 2));
+                }
+                unexplored.push(self.child(index * 2 + 1));
+            } else {
+                if threshold >= distance - radius.clone() {
+                    unexplored.push(self.child(index * 2 + 1));
+                }
+                unexplored.push(self.child(index * 2 + 2));
+            }
+        }
+        count
+    }
+
+    /// Calls `f` with the distance and a reference to every item within `threshold` of
+    /// the needle, as soon as it is found. Items are visited in no particular order.
+    pub fn for_each_within_radius<F: FnMut(Distance, &Item)>(
+        &mut self,
+        needle: &Item,
+        threshold: Distance,
+        mut f: F,
+    ) {
+        self.build();
+        self.search(
+            &self.measure(needle),
+            threshold.clone(),
+            true,
+            &mut Vec::new(),
+            |index, distance| {
+                if distance <= threshold {
+                    f(distance, self.get_item(index));
+                }
+                Some(threshold.clone())
+            },
+        );
+    }
+
+    /// Finds an item at distance zero from the needle, stopping at the first one found
+    /// instead of looking for the nearest neighbor. Only the subtrees that can hold such an
+    /// item are searched, which for most trees is a single path down to a leaf.
+    pub fn find_exact(&mut self, needle: &Item) -> Option<Item>
+    where
+        Item: Clone,
+        Distance: Zero,
+    {
+        self.build();
+        let mut exact = None;
+        self.search(
+            &self.measure(needle),
+            Distance::zero(),
+            true,
+            &mut Vec::new(),
+            |index, distance| {
+                if distance.is_zero() {
+                    exact = Some(index);
+                    None
+                } else {
+                    Some(Distance::zero())
+                }
+            },
+        );
+        exact.map(|index| self.get_item(index).clone())
+    }
+
+    /// Finds the two items closest to each other, or None if the tree holds fewer than two.
+    /// Equal items count as a pair at distance zero, but no item is paired with itself.
+    /// This searches the tree for the nearest neighbor of every item, each search only as
+    /// far as the closest pair found so far, which takes about `len()` times as long as
+    /// `find_nearest_neighbor`: usually much less than comparing every pair of items,
+    /// but no less than that when the distances tell the items apart poorly.
+    pub fn closest_pair(&mut self) -> Option<(Distance, Item, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        let mut closest_pair = None;
+        let mut threshold = Distance::max_value();
+        let mut unexplored = Vec::new();
+        for (first, item) in self.items.iter().enumerate() {
+            self.search(
+                &self.measure(item),
+                threshold.clone(),
+                false,
+                &mut unexplored,
+                |second, distance| {
+                    if second != first && distance < threshold {
+                        closest_pair = Some((first, second));
+                        threshold = distance;
+                    }
+                    Some(threshold.clone())
+                },
+            );
+        }
+        closest_pair.map(|(first, second)| {
+            (
+                threshold,
+                self.get_item(first).clone(),
+                self.get_item(second).clone(),
+            )
+        })
+    }
+
+    /// Finds the k items farthest from the needle, sorted by descending distance.
+    /// If the tree holds fewer than k items, all of them are returned.
+    pub fn find_k_farthest_neighbors(&mut self, needle: &Item, k: usize) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        if k == 0 {
+            return Vec::new();
+        }
+        /* Mirrors consider_item, except that farthest_neighbors is sorted by descending
+        distance and threshold is the distance of the least far one among them */
+        let mut farthest_neighbors: Vec<(Distance, usize)> = Vec::with_capacity(min(k, self.len()));
+        let mut threshold = Distance::min_value();
+        let mut consider = |index: usize, distance: Distance, threshold: &mut Distance| {
+            if farthest_neighbors.len() < k {
+                farthest_neighbors.push((distance, index));
+                if farthest_neighbors.len() == k {
+                    farthest_neighbors.sort_by(|a, b| self.compare_farthest_neighbors(a, b));
+                    *threshold = farthest_neighbors.last().unwrap().0.clone();
+                }
+            } else if distance > *threshold
+                || distance == *threshold
+                    && self.rank(index) < self.rank(farthest_neighbors.last().unwrap().1)
+            {
+                farthest_neighbors.pop();
+                let neighbor = (distance, index);
+                let position = farthest_neighbors.partition_point(|other| {
+                    self.compare_farthest_neighbors(other, &neighbor) == Ordering::Less
+                });
+                farthest_neighbors.insert(position, neighbor);
+                *threshold = farthest_neighbors.last().unwrap().0.clone();
+            }
+        };
+        let pending_start = self.pending_start();
+        for (inner_index, item) in self.leaves()[pending_start..].iter().enumerate() {
+            let distance = self.distance_calculator.distance(needle, item);
+            consider(
+                self.leaf_item_index(pending_start, inner_index),
+                distance,
+                &mut threshold,
+            );
+        }
+        /* Every item in the left subtree of a node is within radius of the vantage point,
+        so none of them can be farther from the needle than the distance to the vantage point
+        plus the radius. The subtree is skipped if that falls short of threshold: items exactly
+        at threshold can still replace one ranked after them. Nothing limits how far items in
+        the right subtree can be, so they inherit their parent's limit. */
+        let mut unexplored: Vec<(usize, Option<(Distance, Distance)>)> = vec![(0, None)];
+        while let Some((index, limit)) = unexplored.pop() {
+            if let Some((distance, radius)) = &limit {
+                if threshold >= *distance && *radius < threshold.clone() - distance.clone() {
+                    continue;
+                }
+            }
+            if let Some(radius) = self.radii.get(index) {
+                let distance = self
+                    .distance_calculator
+                    .distance(needle, &self.items[index]);
+                consider(index, distance.clone(), &mut threshold);
+                // The right subtree is more likely to hold far items, so it's searched first
+                unexplored.push((index * 2 + 1, Some((distance, radius.clone()))));
+                unexplored.push((index * 2 + 2, limit));
+            } else {
+                let mut leaf_index = index - self.radii.len();
+                for (inner_index, item) in self.get_leaf(&mut leaf_index).iter().enumerate() {
+                    let distance = self.distance_calculator.distance(needle, item);
+                    consider(
+                        self.leaf_item_index(leaf_index, inner_index),
+                        distance,
+                        &mut threshold,
+                    );
+                }
+            }
+        }
+        if farthest_neighbors.len() < k {
+            farthest_neighbors.sort_by(|a, b| self.compare_farthest_neighbors(a, b));
+        }
+        farthest_neighbors
+            .into_iter()
+            .map(|(distance, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /// Finds up to k items nearest to the needle, out of those within `threshold` of it.
+    /// Results are sorted by ascending distance.
+    pub fn find_k_nearest_within_radius(
+        &mut self,
+        needle: &Item,
+        k: usize,
+        threshold: Distance,
+    ) -> Vec<(Distance, Item)>
+    where
+        Item: Clone,
+    {
+        self.build();
+        if k == 0 {
+            return Vec::new();
+        }
+        let mut nearest_neighbors = BinaryHeap::with_capacity(min(k, self.len()));
+        let mut radius = threshold.clone();
+        /* A subtree is only worth searching if it may contain items within threshold that are
+        also closer than the k-th nearest neighbor found so far, so the radius of the search
+        shrinks to the latter once there are k candidates. */
+        self.search(
+            &self.measure(needle),
+            threshold.clone(),
+            true,
+            &mut Vec::new(),
+            |index, distance| {
+                if distance <= threshold && distance <= radius {
+                    let k_th = consider_item(
+                        Nearest(distance, self.rank(index), index),
+                        k,
+                        &mut nearest_neighbors,
+                    );
+                    if k_th < threshold {
+                        radius = k_th;
+                    }
+                }
+                Some(radius.clone())
+            },
+        );
+        nearest_neighbors
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Nearest(distance, _, index)| (distance, self.get_item(index).clone()))
+            .collect()
+    }
+
+    /* Calls visit with every item in the subtree at index, which is encoded the same way
+    as the indices search passes to visit */
+    fn for_each_in_subtree<F: FnMut(&Item)>(&self, index: usize, visit: &mut F) {
+        if index < self.radii.len() {
+            visit(&self.items[index]);
+            self.for_each_in_subtree(index * 2 + 1, visit);
+            self.for_each_in_subtree(index * 2 + 2, visit);
+        } else {
+            let mut leaf_index = index - self.radii.len();
+            self.get_leaf(&mut leaf_index).iter().for_each(visit);
+        }
+    }
+
+    /// Checks that the tree is well-formed, returning a description of the first problem
+    /// found. Every item left of a node has to be within its radius and every item right of
+    /// it at or beyond it, which also catches distance calculators that aren't symmetric or
+    /// don't always return the same distance for the same items. This compares every item to
+    /// every vantage point above it, so it is meant for tests and debugging, not for use
+    /// in between queries.
+    pub fn validate(&self) -> Result<(), String> {
+        let leaves_len = leaves_len(self.depth);
+        if self.radii.len() != leaves_len - 1 {
+            return Err(format!(
+                "{} nodes in a tree of depth {}",
+                self.radii.len(),
+                self.depth
+            ));
+        }
+        if self.subtree_lens.len() != self.radii.len() {
+            return Err(format!(
+                "{} subtree lengths for {} nodes",
+                self.subtree_lens.len(),
+                self.radii.len()
+            ));
+        }
+        if self.ids.len() != self.items.len() {
+            return Err(format!(
+                "{} ids for {} items",
+                self.ids.len(),
+                self.items.len()
+            ));
+        }
+        if self.decrementation_point >= leaves_len {
+            return Err(format!(
+                "decrementation point {} with only {} leaves",
+                self.decrementation_point, leaves_len
+            ));
+        }
+        if self.pending_start() > self.leaves().len() {
+            return Err(format!(
+                "{} leaves of size {} and {} of size {} don't fit in {} items",
+                self.decrementation_point,
+                self.leaf_size + 1,
+                leaves_len - self.decrementation_point,
+                self.leaf_size,
+                self.leaves().len()
+            ));
+        }
+        for (index, (vantage_point, radius)) in self.items.iter().zip(&self.radii).enumerate() {
+            let mut result = Ok(());
+            let mut len = 1;
+            self.for_each_in_subtree(index * 2 + 1, &mut |_| len += 1);
+            self.for_each_in_subtree(index * 2 + 2, &mut |_| len += 1);
+            if len != self.subtree_lens[index] {
+                return Err(format!(
+                    "node {} has {} items below it, not {}",
+                    index, len, self.subtree_lens[index]
+                ));
+            }
+            for (child, side, wrong_side) in [
+                (index * 2 + 1, "left", Ordering::Greater),
+                (index * 2 + 2, "right", Ordering::Less),
+            ]
+            .iter()
+            {
+                self.for_each_in_subtree(*child, &mut |item| {
+                    if result.is_err() {
+                        return;
+                    }
+                    let distance = self.distance_calculator.distance(vantage_point, item);
+                    if compare(
+                        &distance,
+                        &self.distance_calculator.distance(item, vantage_point),
+                    ) != Ordering::Equal
+                    {
+                        result = Err(format!(
+                            "distance from node {} to an item differs depending on argument order",
+                            index
+                        ));
+                    } else if compare(&distance, radius) == *wrong_side {
+                        result = Err(format!(
+                            "item on the {} side of node {} is on the wrong side of its radius",
+                            side, index
+                        ));
+                    }
+                });
+            }
+            result?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "ndarray")]
+impl<A, Distance, DistanceCalculator> VPTree<Vec<A>, Distance, DistanceCalculator>
+where
+    A: Clone,
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Vec<A>, Distance = Distance>,
+{
+    /// Creates a tree out of the rows of a two-dimensional array, each of them copied into a
+    /// `Vec` of its own, and builds it right away. Needles are then `Vec`s as well, so that
+    /// the distance functions in `metrics` work with them as they are.
+    pub fn from_rows(
+        rows: ndarray::ArrayView2<A>,
+        distance_calculator: DistanceCalculator,
+    ) -> Self {
+        Self::build_from(
+            rows.outer_iter().map(|row| row.to_vec()),
+            distance_calculator,
+        )
+    }
+}
+
+/// An empty tree, same as `new` with a default distance calculator. Closures and
+/// function pointers don't implement `Default`, so this is for types that implement
+/// `Metric` themselves.
+impl<Item, Distance, DistanceCalculator> Default for VPTree<Item, Distance, DistanceCalculator>
+where
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance> + Default,
+{
+    fn default() -> Self {
+        Self::new(DistanceCalculator::default())
+    }
+}
+
+/// Same as `build_from`, with the default distance calculator, for `Metric` types that hold
+/// nothing: the tree is built once, after all the items are collected.
+impl<Item, Distance, DistanceCalculator> FromIterator<Item>
+    for VPTree<Item, Distance, DistanceCalculator>
+where
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance> + Default,
+{
+    fn from_iter<I: IntoIterator<Item = Item>>(items: I) -> Self {
+        Self::build_from(items, DistanceCalculator::default())
+    }
+}
+
+/// Same as the inherent `extend`: the tree is rebuilt at most once, before the next query.
+impl<Item, Distance, DistanceCalculator> Extend<Item> for VPTree<Item, Distance, DistanceCalculator>
+where
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    fn extend<I: IntoIterator<Item = Item>>(&mut self, items: I) {
+        VPTree::extend(self, items)
+    }
+}
+
+/// Trees are equal if they hold the same items, the same number of times each, no matter
+/// how they are laid out or how they measure distances. This compares every item to
+/// every other, so it is meant for tests more than anything else.
+impl<Item, Distance, DistanceCalculator> PartialEq for VPTree<Item, Distance, DistanceCalculator>
+where
+    Item: PartialEq,
+    Distance: PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        if self.items.len() != other.items.len() {
+            return false;
+        }
+        let mut matched = vec![false; other.items.len()];
+        self.items.iter().all(|item| {
+            match other
+                .items
+                .iter()
+                .zip(matched.iter_mut())
+                .find(|(other_item, matched)| !**matched && *other_item == item)
+            {
+                Some((_, matched)) => {
+                    *matched = true;
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+/// Shows the shape of the tree and the vantage points of its top few levels of nodes.
+impl<Item, Distance, DistanceCalculator> fmt::Debug for VPTree<Item, Distance, DistanceCalculator>
+where
+    Item: fmt::Debug,
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance> + fmt::Debug,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pending = self.leaves().len() - self.pending_start();
+        f.debug_struct("VPTree")
+            .field(
+                "distance_calculator",
+                &format_args!("<distance_calculator>"),
+            )
+            .field("depth", &self.depth)
+            .field("leaf_size", &self.leaf_size)
+            .field("decrementation_point", &self.decrementation_point)
+            .field("vantage_point_strategy", &self.vantage_point_strategy)
+            .field("max_pending", &self.max_pending)
+            .field("max_leaf_size", &self.max_leaf_size)
+            .field("is_updated", &self.is_updated)
+            .field("node_count", &self.radii.len())
+            .field("leaf_items", &(self.leaves().len() - pending))
+            .field("pending_count", &pending)
+            .field(
+                "nodes",
+                &self
+                    .items
+                    .iter()
+                    .zip(&self.radii)
+                    .take(2usize.pow(DEBUG_DEPTH) - 1)
+                    .map(|(vantage_point, radius)| Node {
+                        vantage_point,
+                        radius,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// An iterator over the items of a tree sorted by distance to a needle,
+/// see `VPTree::nearest_neighbors_iter`.
+pub struct NearestNeighbors<'a, Item, Distance, DistanceCalculator>
+where
+    Distance: PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    tree: &'a VPTree<Item, Distance, DistanceCalculator>,
+    needle: &'a Item,
+    candidates: BinaryHeap<Candidate<Distance>>,
+}
+
+impl<Item, Distance, DistanceCalculator> NearestNeighbors<'_, Item, Distance, DistanceCalculator>
+where
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    fn push_item(&mut self, index: usize, distance: Distance) {
+        /* Items at a distance that can't be compared are never returned, see VPTree */
+        if is_comparable(&distance) {
+            self.candidates.push(Candidate {
+                distance,
+                is_item: true,
+                rank: self.tree.rank(index),
+                index,
+            });
+        }
+    }
+
+    /* Replaces the subtree at index with its vantage point and children, or with its items
+    if it's a leaf. bound is the least distance to the needle anything in it can have. */
+    fn expand(&mut self, index: usize, bound: Distance) {
+        let tree = self.tree;
+        if let Some(radius) = tree.radii.get(index) {
+            let distance = tree
+                .distance_calculator
+                .distance(self.needle, &tree.items[index]);
+            /* Everything behind the boundary of the node is at least as far from the needle
+            as the boundary is, see search_best_first */
+            let (near_index, far_index, distance_to_boundary) = if distance < *radius {
+                (
+                    index * 2 + 1,
+                    index * 2 + 2,
+                    radius.clone() - distance.clone(),
+                )
+            } else {
+                (
+                    index * 2 + 2,
+                    index * 2 + 1,
+                    distance.clone() - radius.clone(),
+                )
+            };
+            self.push_item(index, distance);
+            let far_bound = if bound > distance_to_boundary {
+                bound.clone()
+            } else {
+                distance_to_boundary
+            };
+            self.candidates.push(Candidate {
+                distance: far_bound,
+                is_item: false,
+                rank: far_index,
+                index: far_index,
+            });
+            self.candidates.push(Candidate {
+                distance: bound,
+                is_item: false,
+                rank: near_index,
+                index: near_index,
+            });
+        } else {
+            let mut leaf_index = index - tree.radii.len();
+            for (inner_index, item) in tree.get_leaf(&mut leaf_index).iter().enumerate() {
+                let distance = tree.distance_calculator.distance(self.needle, item);
+                self.push_item(tree.leaf_item_index(leaf_index, inner_index), distance);
+            }
+        }
+    }
+}
+
+impl<Item, Distance, DistanceCalculator> Iterator
+    for NearestNeighbors<'_, Item, Distance, DistanceCalculator>
+where
+    Item: Clone,
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    type Item = (Distance, Item);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        /* Nothing left in the heap can be closer than what it pops, so an item that comes
+        out of it is the nearest one that hasn't been returned yet */
+        loop {
+            let candidate = self.candidates.pop()?;
+            if candidate.is_item {
+                let item = self.tree.get_item(candidate.index).clone();
+                return Some((candidate.distance, item));
+            }
+            self.expand(candidate.index, candidate.distance);
+        }
+    }
+}
+
+/// A tree that remembers the result of the last `find_nearest_neighbor`, so that asking for
+/// the nearest neighbor of the same needle again, such as in every frame of an animation
+/// until something moves, doesn't search the tree at all. Any other needle is searched for
+/// as usual and replaces the one remembered. Changing the tree through `tree_mut` forgets it.
+pub struct CachedVPTree<Item, Distance, DistanceCalculator>
+where
+    Distance: PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    tree: VPTree<Item, Distance, DistanceCalculator>,
+    last: Option<(Item, Option<(Distance, Item)>)>,
+}
+
+impl<Item, Distance, DistanceCalculator> CachedVPTree<Item, Distance, DistanceCalculator>
+where
+    Distance: Clone + PartialOrd + Bounded + Sub<Output = Distance>,
+    DistanceCalculator: Metric<Item, Distance = Distance>,
+{
+    pub fn new(tree: VPTree<Item, Distance, DistanceCalculator>) -> Self {
+        Self { tree, last: None }
+    }
+
+    /// Same as `VPTree::find_nearest_neighbor`, but returns the remembered result if the
+    /// needle is equal to the last one.
+    pub fn find_nearest_neighbor(&mut self, needle: &Item) -> Option<(Distance, Item)>
+    where
+        Item: Clone + PartialEq,
+    {
+        match &self.last {
+            Some((last, nearest)) if last == needle => nearest.clone(),
+            _ => {
+                let nearest = self.tree.find_nearest_neighbor(needle);
+                self.last = Some((needle.clone(), nearest.clone()));
+                nearest
+            }
+        }
+    }
+
+    pub fn tree(&self) -> &VPTree<Item, Distance, DistanceCalculator> {
+        &self.tree
+    }
+
+    /// The tree, to be changed or queried in other ways. The remembered result is forgotten,
+    /// since it may not be right anymore.
+    pub fn tree_mut(&mut self) -> &mut VPTree<Item, Distance, DistanceCalculator> {
+        self.last = None;
+        &mut self.tree
+    }
+
+    pub fn into_inner(self) -> VPTree<Item, Distance, DistanceCalculator> {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type PlaneTree = VPTree<(f32, f32), f32, fn(&(f32, f32), &(f32, f32)) -> f32>;
+
+    fn euclidean(a: &(f32, f32), b: &(f32, f32)) -> f32 {
+        ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+    }
+    /* 100 points on a 10 by 10 grid, 7 apart horizontally and 3 apart vertically */
+    fn grid_points() -> Vec<(f32, f32)> {
+        (0..100)
+            .map(|i| ((i % 10) as f32 * 7.0, (i / 10) as f32 * 3.0))
+            .collect()
+    }
+    fn grid_tree() -> PlaneTree {
+        let mut tree: PlaneTree = VPTree::new(euclidean);
+        tree.extend(grid_points());
+        tree
+    }
+    /* len distinct points scattered over a 101 by 103 rectangle, for len up to 10403 */
+    fn scattered_points(len: usize) -> Vec<(f32, f32)> {
+        (0..len)
+            .map(|i| ((i * 37 % 101) as f32, (i * 61 % 103) as f32))
+            .collect()
+    }
+
+    #[test]
+    fn nearest_neigbor_search() {
+        let points = vec![
+            (2.0, 3.0),
+            (0.0, 1.0),
+            (4.0, 5.0),
+            (45.0, 43.0),
+            (21.0, 20.0),
+            (39.0, 44.0),
+            (96.0, 46.0),
+            (95.0, 32.0),
+            (14.0, 63.0),
+            (19.0, 81.0),
+            (66.0, 36.0),
+            (26.0, 64.0),
+            (10.0, 21.0),
+            (92.0, 84.0),
+            (31.0, 55.0),
+            (59.0, 4.0),
+            (43.0, 11.0),
+            (87.0, 56.0),
+            (76.0, 52.0),
+            (10.0, 55.0),
+            (64.0, 97.0),
+            (6.0, 4.0),
+            (10.0, 68.0),
+            (9.0, 8.0),
+            (60.0, 61.0),
+            (22.0, 26.0),
+            (79.0, 52.0),
+            (29.0, 98.0),
+            (88.0, 60.0),
+            (29.0, 97.0),
+            (42.0, 20.0),
+            (5.0, 57.0),
+            (81.0, 58.0),
+            (22.0, 70.0),
+            (44.0, 47.0),
+            (16.0, 6.0),
+            (2.0, 19.0),
+            (26.0, 59.0),
+            (45.0, 34.0),
+            (10.0, 37.0),
+            (8.0, 46.0),
+            (38.0, 6.0),
+            (98.0, 83.0),
+            (18.0, 79.0),
+            (3.0, 81.0),
+            (77.0, 40.0),
+            (82.0, 93.0),
+            (1.0, 65.0),
+            (51.0, 86.0),
+            (34.0, 10.0),
+            (91.0, 16.0),
+            (28.0, 33.0),
+            (5.0, 93.0),
+        ];
+        let mut tree = VPTree::new(|a: &(f32, f32), b: &(f32, f32)| {
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        });
+        tree.extend(points);
+
+        let expected = Some((13.453624, (60.0, 61.0)));
+        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
+        assert_eq!(actual, expected);
+
+        let expected = vec![(4.2426405, (91.0, 16.0)), (13.038404, (95.0, 32.0))];
+        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
+        assert_eq!(actual, expected);
+
+        let actual = tree.find_neighbors_within_radius(&(94.0, 19.0), 13.038404);
+        assert_eq!(actual, expected);
+
+        let expected = vec![
+            (4.472136, (5.0, 57.0)),
+            (6.708204, (10.0, 55.0)),
+            (7.2111025, (1.0, 65.0)),
+            (7.28011, (14.0, 63.0)),
+            (7.615773, (10.0, 68.0)),
+            (15.033297, (8.0, 46.0)),
+            (17.492855, (22.0, 70.0)),
+            (19.104973, (26.0, 59.0)),
+            (19.235384, (26.0, 64.0)),
+            (20.396078, (3.0, 81.0)),
+        ];
+        let actual = tree.find_k_nearest_neighbors(&(7.0, 61.0), 10);
+        assert_eq!(actual, expected);
+
+        let actual = tree.find_neighbors_within_radius(&(7.0, 61.0), 20.396078);
+        assert_eq!(actual, expected);
+
+        let expected = vec![
+            (3.6055512, (87.0, 56.0)),
+            (5.0, (81.0, 58.0)),
+            (5.3851647, (79.0, 52.0)),
+            (7.2111025, (88.0, 60.0)),
+            (8.246211, (76.0, 52.0)),
+            (14.422205, (96.0, 46.0)),
+            (15.652476, (77.0, 40.0)),
+            (24.596748, (95.0, 32.0)),
+            (25.0, (60.0, 61.0)),
+            (25.455845, (66.0, 36.0)),
+            (31.04835, (92.0, 84.0)),
+            (32.202484, (98.0, 83.0)),
+            (38.63936, (91.0, 16.0)),
+            (39.051247, (82.0, 93.0)),
+            (40.5216, (45.0, 43.0)),
+            (40.60788, (44.0, 47.0)),
+            (43.829212, (45.0, 34.0)),
+            (45.96738, (51.0, 86.0)),
+            (46.09772, (39.0, 44.0)),
+            (47.423622, (64.0, 97.0)),
+            (53.009434, (31.0, 55.0)),
+            (54.037025, (42.0, 20.0)),
+            (55.9017, (59.0, 4.0)),
+            (58.21512, (26.0, 59.0)),
+            (58.855755, (26.0, 64.0)),
+            (59.413803, (43.0, 11.0)),
+            (59.808025, (28.0, 33.0)),
+            (64.03124, (22.0, 70.0)),
+            (66.48308, (38.0, 6.0)),
+            (66.6033, (34.0, 10.0)),
+            (68.0294, (22.0, 26.0)),
+            (69.81404, (29.0, 97.0)),
+            (70.38466, (19.0, 81.0)),
+            (70.434364, (29.0, 98.0)),
+            /* Equally far, so in the order they were added */
+            (70.5762, (14.0, 63.0)),
+            (70.5762, (18.0, 79.0)),
+            (71.5891, (21.0, 20.0)),
+            (74.00676, (10.0, 55.0)),
+            (75.31268, (10.0, 68.0)),
+            (75.9276, (10.0, 37.0)),
+            (76.41989, (8.0, 46.0)),
+            (79.05694, (5.0, 57.0)),
+            (81.02469, (10.0, 21.0)),
+            (83.23461, (16.0, 6.0)),
+            (83.725746, (1.0, 65.0)),
+            (85.3815, (3.0, 81.0)),
+            (87.982956, (9.0, 8.0)),
+            (88.10221, (5.0, 93.0)),
+            (89.157166, (2.0, 19.0)),
+            (92.64988, (6.0, 4.0)),
+        ];
+        let actual = tree.find_k_nearest_neighbors(&(84.0, 54.0), 50);
+        assert_eq!(actual, expected);
+
+        let actual = tree.find_neighbors_within_radius(&(84.0, 54.0), 92.64988);
+        assert_eq!(actual, expected);
+    }
+    #[test]
+    fn utility_functions() {
+        let points = vec![(2.0, 3.0), (0.0, 1.0), (4.0, 5.0)];
+        let mut tree = VPTree::new(|a: &(f32, f32), b: &(f32, f32)| {
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        });
+        tree.extend(points);
+        assert_eq!(tree.len(), 3);
+        tree.insert((9.0, 8.0));
+        assert_eq!(tree.len(), 4);
+        tree.extend(vec![(19.0, 81.0), (66.0, 36.0)]);
+        assert_eq!(tree.len(), 6);
+    }
+    #[test]
+    fn tiny_tree() {
+        let points = vec![
+            (2.0, 3.0),
+            (0.0, 1.0),
+            (4.0, 5.0),
+            (45.0, 43.0),
+            (21.0, 20.0),
+            (39.0, 44.0),
+            (96.0, 46.0),
+            (95.0, 32.0),
+            (14.0, 63.0),
+            (19.0, 81.0),
+            (66.0, 36.0),
+            (26.0, 64.0),
+            (10.0, 21.0),
+            (92.0, 84.0),
+            (31.0, 55.0),
+            (59.0, 4.0),
+            (43.0, 11.0),
+            (87.0, 56.0),
+            (76.0, 52.0),
+            (10.0, 55.0),
+            (64.0, 97.0),
+            (6.0, 4.0),
+            (10.0, 68.0),
+            (9.0, 8.0),
+            (60.0, 61.0),
+            (22.0, 26.0),
+            (79.0, 52.0),
+            (29.0, 98.0),
+            (88.0, 60.0),
+            (29.0, 97.0),
+            (42.0, 20.0),
+            (5.0, 57.0),
+            (81.0, 58.0),
+            (22.0, 70.0),
+            (44.0, 47.0),
+            (16.0, 6.0),
+            (2.0, 19.0),
+            (26.0, 59.0),
+            (45.0, 34.0),
+            (10.0, 37.0),
+            (8.0, 46.0),
+            (38.0, 6.0),
+            (98.0, 83.0),
+            (18.0, 79.0),
+            (3.0, 81.0),
+            (77.0, 40.0),
+            (82.0, 93.0),
+            (1.0, 65.0),
+            (51.0, 86.0),
+            (34.0, 10.0),
+            (91.0, 16.0),
+            (28.0, 33.0),
+            (5.0, 93.0),
+        ];
+        let mut tree = VPTree::new(|a: &(f32, f32), b: &(f32, f32)| {
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        });
+        tree.extend(points[0..3].to_vec());
+
+        let expected = Some((92.63369, (4.0, 5.0)));
+        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
+        assert_eq!(actual, expected);
+
+        let expected = vec![(91.08238, (4.0, 5.0)), (93.38094, (2.0, 3.0))];
+        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
+        assert_eq!(actual, expected);
+
+        let mut tree = VPTree::new(|a: &(f32, f32), b: &(f32, f32)| {
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        });
+        tree.extend(points[0..2].to_vec());
+
+        let expected = Some((95.462036, (2.0, 3.0)));
+        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
+        assert_eq!(actual, expected);
+
+        let expected = vec![(93.38094, (2.0, 3.0)), (95.707886, (0.0, 1.0))];
+        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
+        assert_eq!(actual, expected);
+
+        let mut tree = VPTree::new(|a: &(f32, f32), b: &(f32, f32)| {
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        });
+        tree.extend(points[0..1].to_vec());
+
+        let expected = Some((95.462036, (2.0, 3.0)));
+        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
+        assert_eq!(actual, expected);
+
+        let expected = vec![(93.38094, (2.0, 3.0))];
+        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
+        assert_eq!(actual, expected);
+
+        let mut tree = VPTree::new(|a: &(f32, f32), b: &(f32, f32)| {
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        });
+        tree.extend(points[0..0].to_vec());
+
+        let expected = None;
+        let actual = tree.find_nearest_neighbor(&(69.0, 71.0));
+        assert_eq!(actual, expected);
+
+        let expected = vec![];
+        let actual = tree.find_k_nearest_neighbors(&(94.0, 19.0), 2);
+        assert_eq!(actual, expected);
+    }
+    #[test]
+    fn budgeted_search() {
+        let mut tree = grid_tree();
+
+        let expected = tree.find_nearest_neighbor(&(23.0, 13.0));
+        assert_eq!(expected, Some((2.236068, (21.0, 12.0))));
+        let actual = tree.find_nearest_neighbor_budgeted(&(23.0, 13.0), tree.len());
+        assert_eq!(actual, expected);
+
+        // With a budget of one only the root's vantage point gets compared
+        let root = tree.items[0];
+        let distance = ((root.0 - 23.0f32).powi(2) + (root.1 - 13.0f32).powi(2)).sqrt();
+        let actual = tree.find_nearest_neighbor_budgeted(&(23.0, 13.0), 1);
+        assert_eq!(actual, Some((distance, root)));
+
+        let actual = tree.find_nearest_neighbor_budgeted(&(23.0, 13.0), 0);
+        assert_eq!(actual, None);
+    }
+    #[test]
+    fn best_first_search() {
+        let points = scattered_points(500);
+        let mut tree = VPTree::new(euclidean);
+        tree.extend(points);
+        for needle in [(0.0, 0.0), (50.5, 49.0), (120.0, 3.0)].iter() {
+            for &k in [1, 7, 100, 499].iter() {
+                let expected: Vec<f32> = tree
+                    .find_k_nearest_neighbors(needle, k)
+                    .into_iter()
+                    .map(|(distance, _)| distance)
+                    .collect();
+                let actual: Vec<f32> = tree
+                    .find_k_nearest_neighbors_best_first(needle, k)
+                    .into_iter()
+                    .map(|(distance, _)| distance)
+                    .collect();
+                assert_eq!(actual, expected);
+            }
+        }
+    }
+    #[test]
+    fn output_buffer() {
+        let mut tree = grid_tree();
+        let mut out = vec![(0.0, (0.0, 0.0)); 20];
+        for needle in [(23.0, 13.0), (0.0, 0.0), (70.0, -4.0)].iter() {
+            tree.find_k_nearest_neighbors_into(needle, 5, &mut out);
+            assert_eq!(out, tree.find_k_nearest_neighbors(needle, 5));
+        }
+    }
+    #[test]
+    fn query_context() {
+        let mut tree = grid_tree();
+        let mut ctx = QueryContext::new();
+        let mut out = Vec::new();
+        for needle in [(23.0, 13.0), (0.0, 0.0), (70.0, -4.0)].iter() {
+            let expected = tree.find_nearest_neighbor(needle);
+            assert_eq!(tree.find_nearest_neighbor_with(&mut ctx, needle), expected);
+            let expected = tree.find_k_nearest_neighbors(needle, 5);
+            assert_eq!(
+                tree.find_k_nearest_neighbors_with(&mut ctx, needle, 5),
+                expected
+            );
+            tree.find_k_nearest_neighbors_into_with(&mut ctx, needle, 5, &mut out);
+            assert_eq!(out, expected);
+        }
+    }
+    #[test]
+    fn counting() {
+        let mut tree = grid_tree();
+        for &threshold in [0.0, 3.0, 7.0, 15.5, 1000.0].iter() {
+            for needle in [(21.0, 12.0), (0.0, 0.0), (70.0, -4.0)].iter() {
+                let expected = tree.find_neighbors_within_radius(needle, threshold).len();
+                assert_eq!(tree.count_within_radius(needle, threshold), expected);
+            }
+        }
+        assert_eq!(tree.count_within_radius(&(21.0, 12.0), 3.0), 3);
+        assert_eq!(tree.count_within_radius(&(21.0, 12.0), 1000.0), 100);
+    }
+    #[test]
+    fn streaming_within_radius() {
+        let mut tree = grid_tree();
+        let mut actual = Vec::new();
+        tree.for_each_within_radius(&(21.0, 12.0), 7.0, |distance, item| {
+            actual.push((distance, *item))
+        });
+        actual.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let expected = vec![
+            (0.0, (21.0, 12.0)),
+            (3.0, (21.0, 9.0)),
+            (3.0, (21.0, 15.0)),
+            (6.0, (21.0, 6.0)),
+            (6.0, (21.0, 18.0)),
+            (7.0, (14.0, 12.0)),
+            (7.0, (28.0, 12.0)),
+        ];
+        assert_eq!(actual, expected);
+    }
+    #[test]
+    fn insert_without_update() {
+        let mut tree = grid_tree();
+        tree.update();
+        for i in 0..MAX_PENDING {
+            tree.insert((i as f32 + 0.5, 100.0));
+            assert!(tree.is_updated);
+            let expected = Some((0.5, (i as f32 + 0.5, 100.0)));
+            assert_eq!(
+                tree.find_nearest_neighbor(&(i as f32 + 0.5, 100.5)),
+                expected
+            );
+            assert_eq!(tree.count_within_radius(&(0.0, 100.0), 1000.0), 101 + i);
+        }
+        let pending = tree.leaves().len() - tree.pending_start();
+        tree.extend((0..=MAX_PENDING - pending).map(|i| (-5.0 - 10.0 * i as f32, -5.0)));
+        assert!(!tree.is_updated);
+        let expected = vec![(1.0, (-5.0, -5.0)), (6.4031243, (0.0, 0.0))];
+        assert_eq!(tree.find_k_nearest_neighbors(&(-5.0, -4.0), 2), expected);
+        assert!(tree.is_updated);
+        assert_eq!(tree.pending_start(), tree.leaves().len());
+    }
+    #[test]
+    fn equidistant_points() {
+        // Every point on the circle is equally far from the center and from its opposite point
+        let mut points: Vec<(f32, f32)> = (0..64)
+            .map(|i| {
+                let angle = i as f32 * std::f32::consts::PI / 32.0;
+                (10.0 * angle.cos(), 10.0 * angle.sin())
+            })
+            .collect();
+        points.extend(vec![(0.0, 0.0); 20]);
+        let distance = euclidean;
+        let mut tree = VPTree::new(distance);
+        tree.extend(points.clone());
+        tree.update();
+        assert_eq!(tree.radii.len(), 2usize.pow(tree.depth as u32) - 1);
+        for needle in [(0.0, 0.0), (10.0, 0.0), (3.0, -4.0)].iter() {
+            let mut expected: Vec<f32> =
+                points.iter().map(|point| distance(needle, point)).collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for &k in [1, 10, 21, 84].iter() {
+                let actual: Vec<f32> = tree
+                    .find_k_nearest_neighbors(needle, k)
+                    .into_iter()
+                    .map(|(distance, _)| distance)
+                    .collect();
+                assert_eq!(actual, expected[..k].to_vec());
+            }
+            for &threshold in [0.0, 5.0, 10.0].iter() {
+                let expected = expected.iter().filter(|&&d| d <= threshold).count();
+                assert_eq!(tree.count_within_radius(needle, threshold), expected);
+            }
+        }
+    }
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "distance_calculator returned NaN")]
+    fn nan_distance() {
+        let mut tree = VPTree::new(|a: &f32, b: &f32| (a - b).abs());
+        tree.extend(vec![1.0, 2.0, f32::NAN, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        tree.update();
+    }
+
+    #[test]
+    fn nan_ordering() {
+        let mut distances = [2.0, f32::NAN, 1.0, f32::NAN, 0.5];
+        distances.sort_by(compare);
+        assert_eq!(distances[..3], [0.5, 1.0, 2.0]);
+        assert!(distances[3].is_nan() && distances[4].is_nan());
+    }
+    #[test]
+    fn unsigned_distances() {
+        let points: Vec<u64> = (0..300u64)
+            .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 40)
+            .collect();
+        let hamming = |a: &u64, b: &u64| (a ^ b).count_ones();
+        let mut tree = VPTree::new(hamming);
+        tree.extend(points.clone());
+        for needle in [0u64, 0xFF_FFFF, 0x12_3456, points[17]].iter() {
+            let mut expected: Vec<u32> =
+                points.iter().map(|point| hamming(needle, point)).collect();
+            expected.sort_unstable();
+            let actual = tree
+                .find_nearest_neighbor(needle)
+                .map(|(distance, _)| distance);
+            assert_eq!(actual, Some(expected[0]));
+            let actual: Vec<u32> = tree
+                .find_k_nearest_neighbors(needle, 20)
+                .into_iter()
+                .map(|(distance, _)| distance)
+                .collect();
+            assert_eq!(actual, expected[..20].to_vec());
+            for threshold in 0..24 {
+                let count = expected.iter().filter(|&&d| d <= threshold).count();
+                assert_eq!(tree.count_within_radius(needle, threshold), count);
+            }
+        }
+    }
+    #[test]
+    fn clone_distances() {
+        // A distance that is Clone, but not Copy
+        #[derive(Clone, Debug, PartialEq, PartialOrd)]
+        struct Exact(Vec<u64>);
+        impl Bounded for Exact {
+            fn min_value() -> Self {
+                Exact(vec![u64::MIN])
+            }
+            fn max_value() -> Self {
+                Exact(vec![u64::MAX])
+            }
+        }
+        impl Sub for Exact {
+            type Output = Exact;
+            fn sub(self, other: Exact) -> Exact {
+                Exact(vec![self.0[0] - other.0[0]])
+            }
+        }
+        let points: Vec<u64> = (0..100).map(|i| i * i).collect();
+        let mut tree = VPTree::new(|a: &u64, b: &u64| Exact(vec![a.abs_diff(*b)]));
+        tree.extend(points);
+        assert_eq!(tree.find_nearest_neighbor(&51), Some((Exact(vec![2]), 49)));
+        let expected = vec![(Exact(vec![2]), 49), (Exact(vec![13]), 64)];
+        assert_eq!(tree.find_k_nearest_neighbors(&51, 2), expected);
+        assert_eq!(
+            tree.find_neighbors_within_radius(&51, Exact(vec![13])),
+            expected
+        );
+    }
+    #[test]
+    fn farthest_neighbors() {
+        let points = scattered_points(500);
+        let distance = euclidean;
+        let mut tree = VPTree::new(distance);
+        tree.extend(points.clone());
+        for needle in [(0.0, 0.0), (50.5, 49.0), (120.0, 3.0)].iter() {
+            let mut expected: Vec<f32> =
+                points.iter().map(|point| distance(needle, point)).collect();
+            expected.sort_by(|a, b| b.partial_cmp(a).unwrap());
+            for &k in [0, 1, 7, 100, 500, 501].iter() {
+                let actual: Vec<f32> = tree
+                    .find_k_farthest_neighbors(needle, k)
+                    .into_iter()
+                    .map(|(distance, _)| distance)
+                    .collect();
+                assert_eq!(actual, expected[..min(k, expected.len())].to_vec());
+            }
+        }
+    }
+    #[test]
+    fn k_nearest_within_radius() {
+        let points = scattered_points(500);
+        let distance = euclidean;
+        let mut tree = VPTree::new(distance);
+        tree.extend(points.clone());
+        for needle in [(0.0, 0.0), (50.5, 49.0), (120.0, 3.0)].iter() {
+            let mut expected: Vec<f32> =
+                points.iter().map(|point| distance(needle, point)).collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for &k in [0, 1, 7, 100, 600].iter() {
+                for &threshold in [0.0, 10.0, 25.0, 200.0].iter() {
+                    let actual: Vec<f32> = tree
+                        .find_k_nearest_within_radius(needle, k, threshold)
+                        .into_iter()
+                        .map(|(distance, _)| distance)
+                        .collect();
+                    let expected: Vec<f32> = expected
+                        .iter()
+                        .cloned()
+                        .filter(|&distance| distance <= threshold)
+                        .take(k)
+                        .collect();
+                    assert_eq!(actual, expected);
+                }
+            }
+        }
+    }
+    #[test]
+    fn explicit_build() {
+        let mut tree = VPTree::new(|a: &f32, b: &f32| (a - b).abs());
+        tree.extend((0..200).map(|i| i as f32));
+        assert!(!tree.is_updated);
+        tree.build();
+        assert!(tree.is_updated);
+        let root = tree.items[0];
+        tree.extend((200..200 + MAX_PENDING).map(|i| i as f32));
+        tree.build();
+        assert_eq!(tree.items[0], root);
+        let last = (199 + MAX_PENDING) as f32;
+        assert_eq!(
+            tree.find_nearest_neighbor(&1000.0),
+            Some((1000.0 - last, last))
+        );
+    }
+    #[test]
+    fn build_from_iterator() {
+        let mut tree = VPTree::build_from((0..20).map(|i| i as f32 * 2.0), |a: &f32, b: &f32| {
+            (a - b).abs()
+        });
+        assert!(tree.is_updated);
+        assert_eq!(tree.len(), 20);
+        assert_eq!(tree.find_nearest_neighbor(&6.5), Some((0.5, 6.0)));
+    }
+    #[test]
+    fn extend_trait() {
+        fn fill<C: Extend<f32>>(collection: &mut C) {
+            collection.extend((0..10).map(|i| i as f32));
+        }
+        let mut tree = VPTree::new(|a: &f32, b: &f32| (a - b).abs());
+        fill(&mut tree);
+        assert_eq!(tree.len(), 10);
+        assert_eq!(tree.find_nearest_neighbor(&8.75), Some((0.25, 9.0)));
+    }
+    #[test]
+    fn excluding_needle() {
+        let points = grid_points();
+        let mut tree = grid_tree();
+        tree.insert((21.0, 12.0));
+        for point in points.iter() {
+            let (distance, neighbor) = tree.find_nearest_neighbor_excluding(point).unwrap();
+            assert_ne!(&neighbor, point);
+            // Even the duplicate of (21.0, 12.0) is skipped
+            assert_eq!(distance, 3.0);
+        }
+    }
+    #[test]
+    fn predicate_filter() {
+        let points = scattered_points(500);
+        let distance = euclidean;
+        let mut tree = VPTree::new(distance);
+        tree.extend(points.clone());
+        let predicate = |point: &(f32, f32)| (point.0 as u32) % 3 == 0 && point.1 > 20.0;
+        for needle in [(0.0, 0.0), (50.5, 49.0), (120.0, 3.0)].iter() {
+            let mut expected: Vec<f32> = points
+                .iter()
+                .filter(|point| predicate(point))
+                .map(|point| distance(needle, point))
+                .collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let actual = tree
+                .find_nearest_neighbor_matching(needle, predicate)
+                .unwrap();
+            assert!(predicate(&actual.1));
+            assert_eq!(actual.0, expected[0]);
+            let actual = tree.find_k_nearest_neighbors_matching(needle, 10, predicate);
+            assert!(actual.iter().all(|(_, point)| predicate(point)));
+            let actual: Vec<f32> = actual.into_iter().map(|(distance, _)| distance).collect();
+            assert_eq!(actual, expected[..10].to_vec());
+            let actual = tree.find_neighbors_within_radius_matching(needle, 30.0, predicate);
+            assert!(actual.iter().all(|(_, point)| predicate(point)));
+            let expected = expected
+                .iter()
+                .filter(|&&distance| distance <= 30.0)
+                .count();
+            assert_eq!(actual.len(), expected);
+        }
+        assert_eq!(
+            tree.find_nearest_neighbor_matching(&(0.0, 0.0), |_| false),
+            None
+        );
+    }
+    #[test]
+    fn neighbor_struct() {
+        let mut tree = grid_tree();
+        let neighbor: Neighbor<_, _> = tree.find_nearest_neighbor(&(15.0, 7.0)).unwrap().into();
+        assert_eq!(neighbor.item, (14.0, 6.0));
+        let mut heap: BinaryHeap<Neighbor<_, _>> = tree
+            .find_k_nearest_neighbors(&(15.0, 7.0), 5)
+            .into_iter()
+            .map(Neighbor::from)
+            .collect();
+        assert_eq!(heap.len(), 5);
+        let farthest: (f32, (f32, f32)) = heap.pop().unwrap().into();
+        assert!(heap.iter().all(|neighbor| neighbor.distance <= farthest.0));
+
+        let nan = Neighbor::from((f32::NAN, ()));
+        let one = Neighbor::from((1.0, ()));
+        assert!(one < nan);
+        assert_eq!(nan.cmp(&nan), Ordering::Equal);
+    }
+    #[test]
+    fn debug_output() {
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs());
+        tree.extend(0..200);
+        tree.update();
+        tree.extend(Some(200));
+        let output = format!("{:?}", tree);
+        assert!(output.starts_with("VPTree { distance_calculator: <distance_calculator>, "));
+        assert!(output.contains(&format!(
+            "node_count: {}, leaf_items: {}, pending_count: 1",
+            tree.radii.len(),
+            200 - tree.radii.len()
+        )));
+        assert!(output.contains("nodes: [Node { vantage_point: "));
+        assert!(
+            format!("{:#?}", VPTree::new(|a: &i32, b: &i32| (a - b).abs())).contains("nodes: [],")
+        );
+    }
+    #[test]
+    fn validation() {
+        let mut tree = VPTree::new(euclidean);
+        assert_eq!(tree.validate(), Ok(()));
+        for point in scattered_points(200) {
+            tree.insert(point);
+            tree.build();
+            assert_eq!(tree.validate(), Ok(()));
+        }
+        tree.update();
+        assert_eq!(tree.validate(), Ok(()));
+
+        /* Not symmetric: the distance from a to b isn't the distance from b to a */
+        let mut tree = VPTree::build_from(0..100, |a: &i32, b: &i32| (a - 2 * b).abs());
+        assert!(tree.validate().is_err());
+        tree.update();
+        assert!(tree.validate().is_err());
+    }
+    #[test]
+    fn clearing() {
+        let mut tree = VPTree::build_from(0..100, |a: &i32, b: &i32| (a - b).abs());
+        let capacity = tree.radii.capacity() + tree.items.capacity();
+        tree.clear();
+        assert!(tree.is_empty());
+        assert_eq!(tree.radii.capacity() + tree.items.capacity(), capacity);
+        assert_eq!(tree.find_nearest_neighbor(&5), None);
+        assert_eq!(tree.validate(), Ok(()));
+        tree.extend(50..60);
+        assert_eq!(tree.find_nearest_neighbor(&5), Some((45, 50)));
+        assert_eq!(tree.validate(), Ok(()));
+    }
+    #[test]
+    fn tree_shape() {
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs());
+        assert_eq!(tree.depth(), 0);
+        assert_eq!(tree.root_radius(), None);
+        assert_eq!(tree.leaf_count(), 1);
+        tree.extend(0..1000);
+        tree.build();
+        assert_eq!(tree.leaf_count(), 2usize.pow(tree.depth() as u32));
+        assert!(tree.depth() > 0);
+        assert!(tree.len() / tree.leaf_count() <= FLAT_ARRAY_SIZE);
+        let radius = tree.root_radius().unwrap();
+        let root = tree.items[0];
+        let within = (0..1000).filter(|i| (i - root).abs() < radius).count();
+        let beyond = (0..1000).filter(|i| (i - root).abs() > radius).count();
+        assert!(within > 400 && beyond > 400);
+    }
+    #[test]
+    fn batch_search() {
+        let mut tree = VPTree::build_from(scattered_points(500), euclidean);
+        let needles: Vec<(f32, f32)> = (0..50).map(|i| (i as f32 * 2.3, i as f32 * 1.7)).collect();
+        let expected: Vec<_> = needles
+            .iter()
+            .map(|needle| tree.find_nearest_neighbor(needle))
+            .collect();
+        assert_eq!(tree.find_nearest_neighbors_batch(&needles), expected);
+        assert_eq!(tree.find_nearest_neighbors_batch(&[]), Vec::new());
+    }
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_batch_search() {
+        let mut tree = VPTree::build_from(scattered_points(500), euclidean);
+        let needles: Vec<(f32, f32)> = (0..500).map(|i| (i as f32 * 0.3, i as f32 * 0.2)).collect();
+        assert_eq!(
+            tree.par_find_nearest_neighbors_batch(&needles),
+            tree.find_nearest_neighbors_batch(&needles)
+        );
+        /* Items added since the tree was built are searched as well */
+        tree.extend(needles.iter().step_by(10).map(|&(x, y)| (x + 0.1, y)));
+        assert!(!tree.is_updated);
+        let actual = tree.par_find_nearest_neighbors_batch(&needles);
+        assert!(!tree.is_updated);
+        assert_eq!(actual, tree.find_nearest_neighbors_batch(&needles));
+    }
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_update() {
+        let points = scattered_points(5000);
+        let distance = euclidean;
+        let mut sequential = VPTree::build_from(points.clone(), distance);
+        let mut parallel = VPTree::new(distance);
+        parallel.extend(points);
+        parallel.par_update();
+        assert_eq!(parallel.validate(), Ok(()));
+        assert_eq!(format!("{:?}", parallel), format!("{:?}", sequential));
+        assert!(parallel.items == sequential.items);
+        assert!(parallel.radii == sequential.radii);
+        /* Building again from the tree's own layout gives the same result either way too */
+        sequential.update();
+        parallel.par_update();
+        assert!(parallel.items == sequential.items);
+        /* Random vantage points don't depend on the order nodes are built in either */
+        let strategy = VantagePointStrategy::Random { seed: 3 };
+        let mut sequential = sequential.with_vantage_point_strategy(strategy.clone());
+        let mut parallel = parallel.with_vantage_point_strategy(strategy);
+        sequential.update();
+        parallel.par_update();
+        assert!(parallel.items == sequential.items);
+    }
+    #[test]
+    fn vantage_point_strategies() {
+        /* Sorted input is the worst case for always taking the last item */
+        let points: Vec<(f32, f32)> = (0..1000).map(|i| (i as f32, (i % 7) as f32)).collect();
+        let distance = euclidean;
+        for strategy in [
+            VantagePointStrategy::Last,
+            VantagePointStrategy::Random { seed: 7 },
+            VantagePointStrategy::MaxSpread {
+                candidates: 5,
+                samples: 20,
+                seed: 7,
+            },
+            VantagePointStrategy::MaxSpread {
+                candidates: 5,
+                samples: 2,
+                seed: 7,
+            },
+        ]
+        .iter()
+        {
+            let mut tree = VPTree::new(distance).with_vantage_point_strategy(strategy.clone());
+            tree.extend(points.clone());
+            tree.build();
+            assert_eq!(tree.validate(), Ok(()));
+            for needle in [(3.3, 2.0), (500.0, -4.0), (2000.0, 0.0)].iter() {
+                let mut expected: Vec<(f32, (f32, f32))> = points
+                    .iter()
+                    .map(|point| (distance(needle, point), *point))
+                    .collect();
+                expected.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                let actual: Vec<f32> = tree
+                    .find_k_nearest_neighbors(needle, 10)
+                    .into_iter()
+                    .map(|(distance, _)| distance)
+                    .collect();
+                let expected: Vec<f32> = expected[..10].iter().map(|(d, _)| *d).collect();
+                assert_eq!(actual, expected);
+            }
+            /* The same seed builds the same tree */
+            let mut again = VPTree::new(distance).with_vantage_point_strategy(strategy.clone());
+            again.extend(points.clone());
+            again.build();
+            assert!(again.items == tree.items);
+            for needle in [(3.3, 2.0), (500.0, -4.0)].iter() {
+                assert_eq!(
+                    again.find_k_nearest_neighbors(needle, 10),
+                    tree.find_k_nearest_neighbors(needle, 10)
+                );
+            }
+        }
+        /* A different seed builds a different tree */
+        let build = |seed| {
+            let mut tree = VPTree::new(distance)
+                .with_vantage_point_strategy(VantagePointStrategy::Random { seed });
+            tree.extend(points.clone());
+            tree.build();
+            tree.items
+        };
+        assert!(build(7) == build(7));
+        assert!(build(7) != build(8));
+        /* Switching strategies rebuilds the tree */
+        let tree = VPTree::build_from(points, distance);
+        let root = tree.items[0];
+        let mut tree = tree.with_vantage_point_strategy(VantagePointStrategy::Random { seed: 7 });
+        tree.build();
+        assert!(tree.items[0] != root);
+    }
+    #[test]
+    fn degenerate_vantage_point() {
+        /* Points on a circle around the origin, under the manhattan distance, at which
+        they are all exactly 10 away from it */
+        let mut points: Vec<(f32, f32)> = (0..80)
+            .map(|i| -10.0 + i as f32 * 0.25)
+            .flat_map(|x: f32| vec![(x, 10.0 - x.abs()), (-x, x.abs() - 10.0)])
+            .collect();
+        /* The last item would become the root's vantage point */
+        points.push((0.0, 0.0));
+        let distance = |a: &(f32, f32), b: &(f32, f32)| (a.0 - b.0).abs() + (a.1 - b.1).abs();
+        let mut tree = VPTree::build_from(points.clone(), distance);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.depth(), depth(points.len(), FLAT_ARRAY_SIZE));
+        assert!(tree.items[0] != (0.0, 0.0));
+        for needle in [(0.0, 0.0), (10.0, 0.0), (-3.0, 4.0)].iter() {
+            let mut expected: Vec<f32> =
+                points.iter().map(|point| distance(needle, point)).collect();
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let actual: Vec<f32> = tree
+                .find_k_nearest_neighbors(needle, 5)
+                .into_iter()
+                .map(|(distance, _)| distance)
+                .collect();
+            assert_eq!(actual, expected[..5].to_vec());
+        }
+    }
+    #[test]
+    fn shrinking() {
+        let mut tree = VPTree::build_from(0..100, |a: &i32, b: &i32| (a - b).abs());
+        tree.clear();
+        tree.extend(0..10);
+        tree.shrink_to_fit();
+        assert_eq!(tree.radii.capacity() + tree.items.capacity(), 10);
+        assert_eq!(tree.find_nearest_neighbor(&20), Some((11, 9)));
+    }
+    #[test]
+    fn preallocation() {
+        let mut tree = VPTree::with_capacity(|a: &i32, b: &i32| (a - b).abs(), 1000);
+        let items = tree.items.as_ptr();
+        let radii = tree.radii.as_ptr();
+        tree.extend(0..1000);
+        tree.build();
+        assert_eq!(tree.items.as_ptr(), items);
+        assert_eq!(tree.radii.as_ptr(), radii);
+        assert_eq!(tree.find_nearest_neighbor(&-5), Some((5, 0)));
+    }
+    #[test]
+    fn depth_calculation() {
+        assert_eq!(depth(0, FLAT_ARRAY_SIZE), 0);
+        assert_eq!(depth(FLAT_ARRAY_SIZE, FLAT_ARRAY_SIZE), 0);
+        assert_eq!(depth(FLAT_ARRAY_SIZE + 1, FLAT_ARRAY_SIZE), 1);
+        assert_eq!(depth(FLAT_ARRAY_SIZE * 2 + 1, FLAT_ARRAY_SIZE), 1);
+        assert_eq!(depth(FLAT_ARRAY_SIZE * 2 + 2, FLAT_ARRAY_SIZE), 2);
+        /* Too many items for an f32 to tell the two apart */
+        assert_eq!(
+            depth(((FLAT_ARRAY_SIZE + 1) << 24) - 1, FLAT_ARRAY_SIZE),
+            24
+        );
+        assert_eq!(depth((FLAT_ARRAY_SIZE + 1) << 24, FLAT_ARRAY_SIZE), 25);
+        for len in 0..1000 {
+            let leaves = 2usize.pow(depth(len, FLAT_ARRAY_SIZE) as u32);
+            /* Leaves hold at most FLAT_ARRAY_SIZE items, but no fewer than half as many */
+            assert!(len < leaves * (FLAT_ARRAY_SIZE + 1));
+            assert!(leaves == 1 || len + 1 > leaves / 2 * (FLAT_ARRAY_SIZE + 1));
+        }
+    }
+    #[test]
+    fn huge_depth() {
+        for &len in [usize::MAX / 2, usize::MAX - 1, usize::MAX].iter() {
+            let leaves = leaves_len(depth(len, FLAT_ARRAY_SIZE));
+            assert!(leaves.is_power_of_two());
+            assert!(leaves / 2 <= len / (FLAT_ARRAY_SIZE + 1));
+        }
+    }
+    #[test]
+    #[should_panic(expected = "tree is too deep")]
+    fn too_deep() {
+        leaves_len(std::mem::size_of::<usize>() * 8);
+    }
+    #[test]
+    fn retaining() {
+        let mut tree = VPTree::build_from(0..1000, |a: &i32, b: &i32| (a - b).abs());
+        tree.insert(1000);
+        tree.retain(|item| item % 10 == 3);
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.validate(), Ok(()));
+        assert!(tree.is_updated);
+        assert_eq!(tree.find_nearest_neighbor(&500), Some((3, 503)));
+        assert_eq!(tree.find_nearest_neighbor(&2000), Some((1007, 993)));
+        tree.retain(|_| false);
+        assert!(tree.is_empty());
+        assert_eq!(tree.find_nearest_neighbor(&500), None);
+    }
+    #[test]
+    fn instrumented_search() {
+        let points = scattered_points(500);
+        let calls = std::cell::Cell::new(0);
+        let mut tree = VPTree::new(|a: &(f32, f32), b: &(f32, f32)| {
+            calls.set(calls.get() + 1);
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        });
+        tree.extend(points);
+        tree.build();
+        tree.insert((50.0, 50.0));
+        calls.set(0);
+        let (nearest_neighbor, stats) = tree.find_nearest_neighbor_instrumented(&(51.0, 50.0));
+        assert_eq!(nearest_neighbor, tree.find_nearest_neighbor(&(51.0, 50.0)));
+        assert_eq!(stats.distance_calls, calls.get() / 2);
+        assert!(stats.nodes_visited >= tree.depth());
+        assert!(stats.leaves_visited >= 1);
+        assert!(stats.distance_calls < tree.len() / 2);
+        assert!(
+            stats.nodes_visited + tree.leaf_size * stats.leaves_visited <= stats.distance_calls
+        );
+    }
+    #[test]
+    fn deterministic_ties() {
+        let points = grid_points();
+        let distance = |a: &(f32, f32), b: &(f32, f32)| (a.0 - b.0).abs() + (a.1 - b.1).abs();
+        let mut tree = VPTree::build_from(points.clone(), distance);
+        tree.insert((35.0, 12.0));
+        /* Lots of points are the same manhattan distance away from the needle */
+        let needle = (32.0, 12.0);
+        let all = tree.find_k_nearest_neighbors(&needle, 101);
+        let mut items = points.clone();
+        items.push((35.0, 12.0));
+        assert_eq!(
+            all,
+            crate::util::brute_force_k_nearest(&items, &needle, 101, distance)
+        );
+        let radius = all[40].0;
+        let within = tree.find_neighbors_within_radius(&needle, radius);
+        assert_eq!(within, all[..within.len()].to_vec());
+        let k = within.len();
+        assert_eq!(tree.find_k_nearest_neighbors(&needle, k), within);
+        assert_eq!(tree.find_k_nearest_neighbors_best_first(&needle, k), within);
+        assert_eq!(
+            tree.find_k_nearest_within_radius(&needle, k, radius),
+            within
+        );
+        assert_eq!(
+            tree.find_k_nearest_neighbors_matching(&needle, k, |_| true),
+            within
+        );
+        /* The order among equally far items is the same however they're found */
+        let mut farthest = tree.find_k_farthest_neighbors(&needle, 101);
+        farthest.reverse();
+        let distances: Vec<f32> = farthest.iter().map(|(distance, _)| *distance).collect();
+        let expected: Vec<f32> = all.iter().map(|(distance, _)| *distance).collect();
+        assert_eq!(distances, expected);
+    }
+    #[test]
+    fn zero_neighbors() {
+        let mut tree = VPTree::build_from(0..100, |a: &i32, b: &i32| (a - b).abs());
+        assert_eq!(tree.find_k_nearest_neighbors(&5, 0), Vec::new());
+        assert_eq!(tree.find_k_nearest_neighbors_best_first(&5, 0), Vec::new());
+        assert_eq!(
+            tree.find_k_nearest_neighbors_matching(&5, 0, |_| true),
+            Vec::new()
+        );
+        assert_eq!(tree.find_k_nearest_within_radius(&5, 0, 10), Vec::new());
+        assert_eq!(tree.find_k_farthest_neighbors(&5, 0), Vec::new());
+        let mut out = vec![(1, 1)];
+        tree.find_k_nearest_neighbors_into(&5, 0, &mut out);
+        assert!(out.is_empty());
+    }
+    #[test]
+    fn more_neighbors_than_items() {
+        let mut tree = VPTree::build_from((0..10).rev(), |a: &i32, b: &i32| (a - b).abs());
+        tree.insert(20);
+        let expected: Vec<(i32, i32)> = (0..10).map(|i| (i + 7, i)).collect();
+        let all = tree.find_k_nearest_neighbors(&-7, usize::MAX);
+        assert_eq!(all[..10].to_vec(), expected);
+        assert_eq!(all[10], (27, 20));
+        assert_eq!(tree.find_k_nearest_neighbors_best_first(&-7, 1000), all);
+        assert_eq!(
+            tree.find_k_nearest_neighbors_matching(&-7, usize::MAX, |_| true),
+            all
+        );
+        assert_eq!(tree.find_k_nearest_within_radius(&-7, usize::MAX, 100), all);
+        let mut farthest = tree.find_k_farthest_neighbors(&-7, usize::MAX);
+        farthest.reverse();
+        assert_eq!(farthest, all);
+    }
+    #[test]
+    fn changing_metric() {
+        let points = grid_points();
+        let mut tree = VPTree::build_from(points, |a: &(f32, f32), b: &(f32, f32)| {
+            (a.0 - b.0).abs() + (a.1 - b.1).abs()
+        });
+        tree.insert((100.0, 100.0));
+        assert_eq!(
+            tree.find_nearest_neighbor(&(20.0, 5.0)),
+            Some((2.0, (21.0, 6.0)))
+        );
+        /* Same points, but distances are now the number of grid steps, as integers */
+        let mut tree = tree.map_metric(|a: &(f32, f32), b: &(f32, f32)| {
+            ((a.0 - b.0).abs() / 7.0).round() as u32 + ((a.1 - b.1).abs() / 3.0).round() as u32
+        });
+        assert_eq!(tree.len(), 101);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(
+            tree.find_nearest_neighbor(&(63.0, 27.0)),
+            Some((0, (63.0, 27.0)))
+        );
+        assert_eq!(tree.count_within_radius(&(0.0, 0.0), 1), 3);
+    }
+    #[test]
+    fn items_without_clone() {
+        #[derive(Debug, PartialEq)]
+        struct Point(i32);
+        let mut tree = VPTree::new(|a: &Point, b: &Point| (a.0 - b.0).abs());
+        tree.extend((0..100).map(Point));
+        tree.build();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.count_within_radius(&Point(50), 3), 7);
+        let mut found = Vec::new();
+        tree.for_each_within_radius(&Point(-2), 4, |distance, point| {
+            found.push((distance, point.0))
+        });
+        found.sort_unstable();
+        assert_eq!(found, vec![(2, 0), (3, 1), (4, 2)]);
+        assert_eq!(
+            tree.find_k_nearest_neighbors_ref(&Point(-2), 3),
+            vec![(2, &Point(0)), (3, &Point(1)), (4, &Point(2))]
+        );
+        tree.retain(|point| point.0 % 2 == 0);
+        assert_eq!(tree.len(), 50);
+        assert_eq!(tree.count_within_radius(&Point(50), 3), 3);
+    }
+    #[test]
+    fn counting_within_radii() {
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs());
+        tree.extend(0..1000);
+        let thresholds = [0, 1, 5, 5, 30, 2000];
+        let expected: Vec<usize> = thresholds
+            .iter()
+            .map(|&threshold| tree.count_within_radius(&10, threshold))
+            .collect();
+        assert_eq!(expected, vec![1, 3, 11, 11, 41, 1000]);
+        assert_eq!(tree.count_within_radii(&10, &thresholds), expected);
+        assert_eq!(tree.count_within_radii(&10, &[]), Vec::<usize>::new());
+        assert_eq!(tree.count_within_radii(&-10, &[5, 10]), vec![0, 1]);
+    }
+    #[test]
+    #[should_panic(expected = "thresholds are not sorted in ascending order")]
+    fn unsorted_radii() {
+        let mut tree = VPTree::build_from(0..10, |a: &i32, b: &i32| (a - b).abs());
+        tree.count_within_radii(&0, &[3, 1]);
+    }
+    #[test]
+    fn nearest_distance() {
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs());
+        assert_eq!(tree.nearest_neighbor_distance(&3), None);
+        tree.extend((0..100).map(|i| i * 10));
+        for needle in [-7, 0, 254, 996, 2000].iter() {
+            assert_eq!(
+                tree.nearest_neighbor_distance(needle),
+                tree.find_nearest_neighbor(needle)
+                    .map(|(distance, _)| distance)
+            );
+        }
+        assert_eq!(tree.nearest_neighbor_distance(&254), Some(4));
+    }
+    #[test]
+    fn unsorted_radius_search() {
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs());
+        tree.extend(0..1000);
+        tree.insert(500);
+        let mut unsorted = tree.find_neighbors_within_radius_unsorted(&500, 20);
+        assert_eq!(unsorted.len(), 42);
+        unsorted.sort_unstable();
+        let mut sorted = tree.find_neighbors_within_radius(&500, 20);
+        sorted.sort_unstable();
+        assert_eq!(unsorted, sorted);
+    }
+    #[test]
+    fn cloning() {
+        let offset = 0;
+        let mut tree = VPTree::build_from(0..100, move |a: &i32, b: &i32| (a - b).abs() + offset);
+        let mut snapshot = tree.clone();
+        tree.retain(|&item| item > 50);
+        tree.insert(-1);
+        assert_eq!(snapshot.len(), 100);
+        assert_eq!(snapshot.validate(), Ok(()));
+        assert_eq!(snapshot.find_nearest_neighbor(&20), Some((0, 20)));
+        assert_eq!(tree.find_nearest_neighbor(&20), Some((21, -1)));
+        let mut copy = snapshot.clone();
+        assert_eq!(
+            copy.find_k_nearest_neighbors(&20, 5),
+            snapshot.find_k_nearest_neighbors(&20, 5)
+        );
+    }
+    #[test]
+    fn reserving() {
+        let mut tree = VPTree::build_from(0..100, |a: &i32, b: &i32| (a - b).abs());
+        tree.reserve(900);
+        let items = tree.items.as_ptr();
+        let radii = tree.radii.as_ptr();
+        for item in 100..1000 {
+            tree.insert(item);
+        }
+        tree.build();
+        assert_eq!(tree.items.as_ptr(), items);
+        assert_eq!(tree.radii.as_ptr(), radii);
+        assert_eq!(tree.len(), 1000);
+        assert_eq!(tree.find_nearest_neighbor(&2000), Some((1001, 999)));
+    }
+    #[test]
+    fn into_items() {
+        let mut tree = VPTree::build_from(0..100, |a: &i32, b: &i32| (a - b).abs());
+        tree.insert(100);
+        let mut items = tree.into_items();
+        items.sort_unstable();
+        assert_eq!(items, (0..=100).collect::<Vec<_>>());
+    }
+    #[test]
+    fn deduplication() {
+        let items = (0..300).map(|i| (i % 100, i % 7 == 0));
+        let distance = |a: &(i32, bool), b: &(i32, bool)| (a.0 - b.0).abs();
+        let (mut tree, duplicate_count) = VPTree::new_dedup(items.clone(), distance);
+        assert_eq!(duplicate_count, 300 - tree.len());
+        assert_eq!(tree.len(), 143);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.count_within_radius(&(49, false), 0), 2);
+        assert_eq!(tree.dedup(), 0);
+
+        let mut tree = VPTree::build_from(items, distance);
+        tree.insert((0, true));
+        assert_eq!(tree.len(), 301);
+        assert_eq!(tree.dedup(), duplicate_count + 1);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.find_k_nearest_neighbors(&(3, false), 3).len(), 3);
+        assert_eq!(tree.count_within_radius(&(49, false), 0), 2);
+    }
+    #[test]
+    fn nearest_neighbors_iterator() {
+        let points: Vec<(f32, f32)> = (0..500)
+            .map(|i| ((i % 23) as f32, (i % 17) as f32 * 0.5))
+            .collect();
+        let distance = euclidean;
+        let mut tree = VPTree::build_from(points.clone(), distance);
+        tree.insert((3.3, 3.3));
+        for needle in [(3.0, 4.0), (-10.0, 0.0), (11.5, 2.25)].iter() {
+            let all = tree.find_k_nearest_neighbors(needle, 501);
+            let iterated: Vec<_> = tree.nearest_neighbors_iter(needle).collect();
+            assert_eq!(iterated, all);
+            let first: Vec<_> = tree.nearest_neighbors_iter(needle).take(7).collect();
+            assert_eq!(first, all[..7]);
+        }
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs());
+        assert_eq!(tree.nearest_neighbors_iter(&0).next(), None);
+        tree.extend(vec![5, 1, 9]);
+        assert_eq!(
+            tree.nearest_neighbors_iter(&2).collect::<Vec<_>>(),
+            vec![(1, 1), (3, 5), (7, 9)]
+        );
+    }
+    #[test]
+    fn uneven_leaves() {
+        /* Sizes for which some of the leaves hold one item more than the others, so that
+        decoding the index of an item in a leaf has to take both lengths into account */
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        for len in (1..200).filter(|len| {
+            let tree = VPTree::build_from(0..*len, distance);
+            tree.decrementation_point > 0 && tree.depth > 0
+        }) {
+            let mut tree = VPTree::build_from((0..len).map(|i| i * 3), distance);
+            assert_eq!(tree.validate(), Ok(()));
+            for item in 0..len {
+                assert_eq!(
+                    tree.find_nearest_neighbor(&(item * 3 + 1)),
+                    Some((1, item * 3))
+                );
+            }
+        }
+    }
+    #[test]
+    fn leaf_occupancy() {
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs());
+        assert_eq!(tree.leaf_occupancy(), (0, 0, 0.0));
+        for len in 1..300 {
+            tree.clear();
+            tree.extend(0..len);
+            tree.build();
+            let (least, most, mean) = tree.leaf_occupancy();
+            let leaf_items = (tree.len() - tree.radii.len()) as f64;
+            assert!((mean - leaf_items / tree.leaf_count() as f64).abs() < 1e-9);
+            assert!(least as f64 <= mean && mean <= most as f64);
+            assert!(most - least <= 1);
+        }
+    }
+    #[test]
+    fn bulk_insertion() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let mut tree = VPTree::build_from(0..10, distance);
+        let radii = tree.radii.clone();
+        for item in 10..1000 {
+            tree.insert(item);
+        }
+        tree.extend(1000..2000);
+        /* Nothing has been rebuilt yet */
+        assert!(tree.radii == radii);
+        assert!(!tree.is_updated);
+        tree.build();
+        assert!(tree.is_updated);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.depth(), depth(2000, FLAT_ARRAY_SIZE));
+        assert_eq!(tree.find_nearest_neighbor(&1500), Some((0, 1500)));
+    }
+    #[test]
+    fn equality() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let tree = VPTree::build_from(0..100, distance);
+        let mut other = VPTree::new(distance)
+            .with_vantage_point_strategy(VantagePointStrategy::Random { seed: 1 });
+        other.extend((0..100).rev());
+        assert!(tree == other);
+        other.build();
+        assert!(tree.radii != other.radii);
+        assert!(tree == other);
+        other.insert(5);
+        assert!(tree != other);
+        let mut with_duplicate = VPTree::build_from((1..100).chain(Some(5)), distance);
+        assert!(with_duplicate != tree);
+        with_duplicate.retain(|&item| item != 5);
+        with_duplicate.extend(vec![0, 5]);
+        assert!(with_duplicate == tree);
+    }
+    #[test]
+    fn needle_of_another_type() {
+        /* Items that hold a name along with their coordinates */
+        let items: Vec<((f32, f32), String)> = (0..300)
+            .map(|i| (((i % 20) as f32, (i / 20) as f32), format!("item {}", i)))
+            .collect();
+        let distance = euclidean;
+        let mut tree = VPTree::build_from(
+            items,
+            move |a: &((f32, f32), String), b: &((f32, f32), String)| distance(&a.0, &b.0),
+        );
+        let needle = (4.2, 7.1);
+        let to_needle = |item: &((f32, f32), String)| distance(&needle, &item.0);
+        let dummy = (needle, String::new());
+        assert_eq!(
+            tree.find_nearest_neighbor_by(to_needle),
+            tree.find_nearest_neighbor(&dummy)
+        );
+        assert_eq!(
+            tree.find_nearest_neighbor_by(to_needle)
+                .map(|(_, (_, name))| name),
+            Some("item 144".to_string())
+        );
+        assert_eq!(
+            tree.find_k_nearest_neighbors_by(10, to_needle),
+            tree.find_k_nearest_neighbors(&dummy, 10)
+        );
+        assert_eq!(
+            tree.find_neighbors_within_radius_by(2.5, to_needle),
+            tree.find_neighbors_within_radius(&dummy, 2.5)
+        );
+    }
+    #[test]
+    fn closest_pair() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let mut tree = VPTree::new(distance);
+        assert_eq!(tree.closest_pair(), None);
+        tree.insert(7);
+        assert_eq!(tree.closest_pair(), None);
+        let items: Vec<i32> = (0..500).map(|i| i * i * 7 % 100_003).collect();
+        tree.extend(items.clone());
+        let (closest, a, b) = tree.closest_pair().unwrap();
+        let mut items = items;
+        items.push(7);
+        items.sort_unstable();
+        let expected = items
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .min()
+            .unwrap();
+        assert_eq!(closest, expected);
+        assert_eq!(distance(&a, &b), expected);
+        /* Equal items are the closest pair there can be */
+        tree.insert(a);
+        assert_eq!(tree.closest_pair(), Some((0, a, a)));
+    }
+    #[test]
+    fn exact_match() {
+        let points: Vec<(f32, f32)> = (0..1000)
+            .map(|i| ((i % 37) as f32, (i / 37) as f32))
+            .collect();
+        let distance = euclidean;
+        let mut tree = VPTree::build_from(points.clone(), distance);
+        for point in points.iter().step_by(13) {
+            assert_eq!(tree.find_exact(point), Some(*point));
+        }
+        assert_eq!(tree.find_exact(&(0.5, 0.0)), None);
+        assert_eq!(tree.find_exact(&(-1.0, 3.0)), None);
+        tree.insert((0.5, 0.0));
+        assert_eq!(tree.find_exact(&(0.5, 0.0)), Some((0.5, 0.0)));
+        /* Items at distance zero don't have to be equal to the needle */
+        let mut tree = VPTree::build_from(0..100, |a: &i32, b: &i32| (a / 10 - b / 10).abs());
+        assert_eq!(tree.find_exact(&55).map(|item| item / 10), Some(5));
+    }
+    #[test]
+    fn zero_radius() {
+        let mut tree = VPTree::build_from(
+            (0..300u32).map(|i| [(i % 50) as u8, 0]),
+            crate::metrics::hamming,
+        );
+        let within = tree.find_neighbors_within_radius(&[7, 0], u32::zero());
+        assert_eq!(within, vec![(0, [7, 0]); 6]);
+        assert_eq!(tree.count_within_radius(&[7, 0], 0), 6);
+        assert_eq!(tree.count_within_radius(&[7, 1], 0), 0);
+        /* Negative zero is at distance zero from zero */
+        let points: Vec<[f64; 2]> = (0..200)
+            .map(|i| [(i % 10) as f64 - 5.0, -((i / 10) as f64) * 0.0])
+            .collect();
+        let mut tree = VPTree::build_from(points, crate::metrics::euclidean);
+        let within = tree.find_neighbors_within_radius(&[0.0, 0.0], f64::zero());
+        assert_eq!(within.len(), 20);
+        assert!(within
+            .iter()
+            .all(|(distance, point)| *distance == 0.0 && point[0] == 0.0));
+        assert_eq!(tree.find_exact(&[0.0, 0.0]), Some([0.0, 0.0]));
+    }
+    #[test]
+    fn mapping_items_in_place() {
+        let mut tree = VPTree::build_from(
+            (0..200).map(|i| (i, 0)),
+            |a: &(i32, i32), b: &(i32, i32)| (a.0 - b.0).abs(),
+        );
+        tree.insert((200, 0));
+        let radii = tree.radii.clone();
+        tree.map_items_in_place(|item| item.1 = item.0 / 50);
+        assert!(tree.radii == radii);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.find_nearest_neighbor(&(120, 9)), Some((0, (120, 2))));
+        assert_eq!(tree.find_nearest_neighbor(&(300, 9)), Some((100, (200, 4))));
+    }
+    #[test]
+    fn earliest_vantage_points() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let items: Vec<i32> = (0..500).map(|i| i * 7919 % 500).collect();
+        let mut tree =
+            VPTree::new(distance).with_vantage_point_strategy(VantagePointStrategy::Earliest);
+        tree.extend(items.clone());
+        tree.build();
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.items[0], items[0]);
+        /* Every vantage point was added before everything below it */
+        for index in 0..tree.radii.len() {
+            let id = tree.ids[index];
+            let mut earliest = true;
+            tree.for_each_in_subtree(index * 2 + 1, &mut |item| {
+                earliest &= items.iter().position(|other| other == item).unwrap() > id
+            });
+            tree.for_each_in_subtree(index * 2 + 2, &mut |item| {
+                earliest &= items.iter().position(|other| other == item).unwrap() > id
+            });
+            assert!(earliest);
+        }
+        let mut again =
+            VPTree::new(distance).with_vantage_point_strategy(VantagePointStrategy::Earliest);
+        again.extend(items.clone());
+        again.build();
+        assert!(again.items == tree.items);
+        /* Removing items keeps the order the rest were added in */
+        tree.retain(|item| item % 3 != 0);
+        let first = *items.iter().find(|item| *item % 3 != 0).unwrap();
+        assert_eq!(tree.items[0], first);
+        assert_eq!(tree.find_nearest_neighbor(&299), Some((0, 299)));
+        assert_eq!(
+            tree.find_nearest_neighbor(&300)
+                .map(|(distance, _)| distance),
+            Some(1)
+        );
+    }
+    #[test]
+    fn preselected_vantage_points() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let items: Vec<i32> = (0..1000).collect();
+        /* 400 is within the radius of 500 and 100 isn't */
+        let strategy = VantagePointStrategy::PreselectedVantagePoints(vec![500, 400, 100]);
+        let mut tree = VPTree::new_with_strategy(items.clone(), distance, strategy);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.items[..3], [500, 400, 100]);
+        for needle in [-5, 333, 500, 999].iter() {
+            assert_eq!(
+                tree.find_k_nearest_neighbors(needle, 5),
+                crate::util::brute_force_k_nearest(&items, needle, 5, distance)
+            );
+        }
+        /* 100 can't be the vantage point of a node within the radius of 500 */
+        let strategy = VantagePointStrategy::PreselectedVantagePoints(vec![500, 100]);
+        let tree = VPTree::new_with_strategy(items, distance, strategy);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.items[0], 500);
+        assert_ne!(tree.items[1], 100);
+    }
+    #[test]
+    fn indexed_queries() {
+        let items: Vec<i32> = (0..300).map(|i| i * 7919 % 1000).collect();
+        let mut tree = VPTree::build_from(items.clone(), |a: &i32, b: &i32| (a - b).abs());
+        tree.insert(2000);
+        let mut items = items;
+        items.push(2000);
+        let (distance, index) = tree.find_nearest_neighbor_indexed(&1990).unwrap();
+        assert_eq!((distance, index), (10, 300));
+        for needle in [-5, 500, 777].iter() {
+            let (distance, index) = tree.find_nearest_neighbor_indexed(needle).unwrap();
+            assert_eq!(
+                Some((distance, items[index])),
+                tree.find_nearest_neighbor(needle)
+            );
+            let indexed: Vec<_> = tree
+                .find_k_nearest_neighbors_indexed(needle, 10)
+                .into_iter()
+                .map(|(distance, index)| (distance, items[index]))
+                .collect();
+            assert_eq!(indexed, tree.find_k_nearest_neighbors(needle, 10));
+            let indexed: Vec<_> = tree
+                .find_neighbors_within_radius_indexed(needle, 30)
+                .into_iter()
+                .map(|(distance, index)| (distance, items[index]))
+                .collect();
+            assert_eq!(indexed, tree.find_neighbors_within_radius(needle, 30));
+        }
+        /* Removing items doesn't change the numbers of the others */
+        tree.retain(|item| item % 2 == 0);
+        let (_, index) = tree.find_nearest_neighbor_indexed(&1990).unwrap();
+        assert_eq!(index, 300);
+        let (distance, index) = tree.find_nearest_neighbor_indexed(&501).unwrap();
+        assert_eq!(items[index] % 2, 0);
+        assert_eq!(distance, (items[index] - 501).abs());
+    }
+    #[test]
+    fn max_pending() {
+        let mut tree =
+            VPTree::build_from(0..100, |a: &i32, b: &i32| (a - b).abs()).with_max_pending(20);
+        assert!(!tree.rebalance_if_needed());
+        for i in 100..120 {
+            tree.insert(i);
+            assert!(tree.is_updated);
+        }
+        assert_eq!(tree.find_nearest_neighbor(&130), Some((11, 119)));
+        assert!(!tree.rebalance_if_needed());
+        tree.insert(120);
+        assert!(tree.rebalance_if_needed());
+        assert_eq!(tree.pending_start(), tree.leaves().len());
+        assert_eq!(tree.validate(), Ok(()));
+
+        /* Lowering the limit below the number of items already pending takes effect at once */
+        tree.extend(121..126);
+        let mut tree = tree.with_max_pending(0);
+        assert!(!tree.is_updated);
+        assert_eq!(tree.find_nearest_neighbor(&130), Some((5, 125)));
+        assert!(tree.is_updated);
+        tree.insert(126);
+        assert!(tree.rebalance_if_needed());
+        assert_eq!(
+            tree.map_metric(|a: &i32, b: &i32| (a - b).abs() * 2)
+                .max_pending,
+            0
+        );
+    }
+    #[test]
+    fn deterministic_builds() {
+        let distance = |a: &(i32, i32), b: &(i32, i32)| (a.0 - b.0).abs() + (a.1 - b.1).abs();
+        let points: Vec<(i32, i32)> = (0..300).map(|i| (i % 17, i % 23)).collect();
+        let mut shuffled = points.clone();
+        shuffled.reverse();
+        shuffled.rotate_left(101);
+        for strategy in [
+            VantagePointStrategy::Last,
+            VantagePointStrategy::Random { seed: 3 },
+        ]
+        .iter()
+        {
+            let mut trees: Vec<_> = [&points, &shuffled]
+                .iter()
+                .map(|items| {
+                    let mut tree = VPTree::new_deterministic(distance)
+                        .with_vantage_point_strategy(strategy.clone());
+                    tree.extend(items.iter().cloned());
+                    tree.update();
+                    tree
+                })
+                .collect();
+            assert_eq!(trees[0].items, trees[1].items);
+            assert_eq!(trees[0].radii, trees[1].radii);
+            for needle in [(5, 5), (0, 30), (8, 11)].iter() {
+                assert_eq!(
+                    trees[0].find_k_nearest_neighbors(needle, 20),
+                    trees[1].find_k_nearest_neighbors(needle, 20)
+                );
+            }
+        }
+    }
+    #[test]
+    fn memory_footprint() {
+        let mut tree = VPTree::with_capacity(|a: &u64, b: &u64| a.max(b) - a.min(b), 1000);
+        assert!(tree.capacity() >= 1000);
+        assert!(tree.memory_footprint() >= 1000 * (8 + size_of::<usize>()));
+        tree.extend(0..100);
+        tree.build();
+        tree.shrink_to_fit();
+        assert_eq!(tree.capacity(), 100);
+        assert_eq!(
+            tree.memory_footprint(),
+            (100 + tree.radii.len()) * (8 + size_of::<usize>())
+        );
+        tree.clear();
+        tree.shrink_to_fit();
+        assert_eq!(tree.memory_footprint(), 0);
+    }
+    #[test]
+    fn rings() {
+        let mut tree = VPTree::build_from(-50..=50, |a: &i32, b: &i32| (a - b).abs());
+        let rings = tree.neighbors_by_ring(&0, 10, 3);
+        assert_eq!(rings.len(), 3);
+        assert_eq!(rings[0].len(), 19);
+        assert_eq!(rings[0][0], 0);
+        assert!(rings[1].contains(&-10) && rings[1].contains(&19) && !rings[1].contains(&20));
+        assert_eq!(rings[2].len(), 20);
+        assert!(rings.iter().all(|ring| !ring.contains(&30)));
+        assert!(tree.neighbors_by_ring(&0, 10, 0).is_empty());
+        assert_eq!(
+            tree.neighbors_by_ring(&0, 0, 2),
+            vec![Vec::new(), Vec::new()]
+        );
+
+        let mut tree = VPTree::build_from(
+            (0..400).map(|i| [(i % 20) as f64 * 0.5, (i / 20) as f64 * 0.5]),
+            crate::metrics::euclidean,
+        );
+        let needle = [3.3, 4.1];
+        let rings = tree.neighbors_by_ring(&needle, 0.75, 4);
+        for (i, ring) in rings.iter().enumerate() {
+            let expected: Vec<_> = tree
+                .find_neighbors_within_radius(&needle, 0.75 * (i + 1) as f64)
+                .into_iter()
+                .filter(|(distance, _)| {
+                    *distance >= 0.75 * i as f64 && *distance < 0.75 * (i + 1) as f64
+                })
+                .map(|(_, point)| point)
+                .collect();
+            assert_eq!(ring, &expected);
+        }
+    }
+    #[test]
+    fn insert_outcomes() {
+        let mut tree =
+            VPTree::new_deterministic(|a: &i32, b: &i32| (a - b).abs()).with_max_pending(2);
+        assert_eq!(tree.try_insert(0), InsertOutcome::TriggeredRebuild);
+        tree.build();
+        assert_eq!(tree.try_insert(1), InsertOutcome::AppendedToLeaf);
+        assert_eq!(tree.try_insert(2), InsertOutcome::AppendedToLeaf);
+        assert_eq!(tree.try_insert(3), InsertOutcome::TriggeredRebuild);
+        assert_eq!(tree.try_insert(4), InsertOutcome::TriggeredRebuild);
+        assert_eq!(tree.find_nearest_neighbor(&5), Some((1, 4)));
+        assert_eq!(tree.try_insert(5), InsertOutcome::AppendedToLeaf);
+
+        let mut tree = VPTree::build_from(0..1000, |a: &i32, b: &i32| (a - b).abs());
+        let mut outcomes = [0; 3];
+        for item in (0..300).map(|i| i * 37 % 1000) {
+            match tree.try_insert(item) {
+                InsertOutcome::AppendedToLeaf => outcomes[0] += 1,
+                InsertOutcome::RebuiltSubtree { node_index } => {
+                    assert!(tree.subtree_len(node_index) <= MAX_PENDING);
+                    if node_index < tree.radii.len() {
+                        outcomes[1] += 1;
+                    }
+                }
+                InsertOutcome::TriggeredRebuild => {
+                    outcomes[2] += 1;
+                    tree.build();
+                }
+            }
+            assert_eq!(tree.validate(), Ok(()));
+        }
+        assert!(outcomes.iter().all(|&count| count > 0), "{:?}", outcomes);
+    }
+    #[test]
+    fn inserting_into_subtrees() {
+        let points = scattered_points(1100);
+        for strategy in [
+            VantagePointStrategy::Last,
+            VantagePointStrategy::Random { seed: 5 },
+            VantagePointStrategy::Earliest,
+            VantagePointStrategy::PreselectedVantagePoints(vec![7, 300, 900]),
+        ]
+        .iter()
+        {
+            let tree: PlaneTree =
+                VPTree::new_with_strategy(points[..1000].to_vec(), euclidean, strategy.clone());
+            let mut tree = tree.with_max_pending(usize::MAX);
+            let mut pending = 0;
+            for point in &points[1000..] {
+                let outcome = tree.try_insert(*point);
+                assert_eq!(tree.validate(), Ok(()));
+                let now_pending = tree.leaves().len() - tree.pending_start();
+                if now_pending > pending {
+                    assert_eq!(outcome, InsertOutcome::AppendedToLeaf);
+                } else {
+                    assert!(matches!(outcome, InsertOutcome::RebuiltSubtree { .. }));
+                }
+                pending = now_pending;
+            }
+            assert!(pending < 100, "{} of 100 inserts are pending", pending);
+            for needle in [(0.0, 0.0), (50.5, 20.0), (100.0, 103.0)].iter() {
+                assert_eq!(
+                    tree.find_k_nearest_neighbors(needle, 10),
+                    crate::util::brute_force_k_nearest(&points, needle, 10, euclidean)
+                );
+            }
+        }
+    }
+    #[test]
+    fn k_nearest_distinct_ties() {
+        let distance = |a: &(i32, u8), b: &(i32, u8)| (a.0 - b.0).abs();
+        let items: Vec<(i32, u8)> = (0..300).map(|i| (i * 7 % 40, (i % 13) as u8)).collect();
+        let mut built = VPTree::build_from(items[..200].to_vec(), distance);
+        built.extend(items[200..].iter().copied());
+        let random = VPTree::new_with_strategy(
+            items.clone(),
+            distance,
+            VantagePointStrategy::Random { seed: 11 },
+        );
+        for mut tree in [built, random] {
+            for needle in [(-3, 0), (0, 0), (17, 0), (20, 0), (45, 0)].iter() {
+                for &k in [1, 4, 13, 20].iter() {
+                    /* The first of the nearest items of every key, then the first of the
+                    nearest keys, which is what ordering by insertion comes down to */
+                    let mut expected: Vec<(i32, usize)> = Vec::new();
+                    for (index, item) in items.iter().enumerate() {
+                        let nearer = |(distance_found, found): &(i32, usize)| {
+                            items[*found].1 == item.1 && *distance_found <= distance(needle, item)
+                        };
+                        if !expected.iter().any(nearer) {
+                            expected.retain(|(_, found)| items[*found].1 != item.1);
+                            expected.push((distance(needle, item), index));
+                        }
+                    }
+                    expected.sort();
+                    expected.truncate(k);
+                    let expected: Vec<(i32, (i32, u8))> = expected
+                        .into_iter()
+                        .map(|(distance, index)| (distance, items[index]))
+                        .collect();
+                    assert_eq!(
+                        tree.find_k_nearest_distinct_by(needle, k, |item| item.1),
+                        expected
+                    );
+                }
+            }
+        }
+    }
+    #[test]
+    fn k_nearest_distinct() {
+        let distance = |a: &(f64, f64, u8), b: &(f64, f64, u8)| {
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        };
+        let items: Vec<(f64, f64, u8)> = (0..500u32)
+            .map(|i| {
+                let x = (i * 7919 % 1000) as f64 / 10.0;
+                let y = (i * 104729 % 997) as f64 / 10.0;
+                (x, y, (i * 31 % 23) as u8)
+            })
+            .collect();
+        let mut tree = VPTree::build_from(items.clone(), distance);
+        for needle in [(50.0, 50.0, 0), (0.0, 0.0, 0), (99.0, 10.0, 0)].iter() {
+            for &k in [0, 1, 5, 23, 30].iter() {
+                let mut expected: Vec<(f64, u8)> = Vec::new();
+                for category in 0..23 {
+                    let nearest = items
+                        .iter()
+                        .filter(|item| item.2 == category)
+                        .map(|item| distance(needle, item))
+                        .fold(f64::INFINITY, f64::min);
+                    expected.push((nearest, category));
+                }
+                expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                expected.truncate(k);
+                let found = tree.find_k_nearest_distinct_by(needle, k, |item| item.2);
+                assert_eq!(
+                    found
+                        .iter()
+                        .map(|(distance, item)| (*distance, item.2))
+                        .collect::<Vec<_>>(),
+                    expected
+                );
+            }
+        }
+    }
+    #[test]
+    fn build_from_unique() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let tree = VPTree::build_from_unique((0..200).map(|i| i * 7 % 200), distance);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(
+            tree.items,
+            VPTree::build_from((0..200).map(|i| i * 7 % 200), distance).items
+        );
+    }
+    #[test]
+    fn k_nearest_in_region() {
+        use std::cell::Cell;
+        let in_box = |point: &(f64, f64)| point.0 > 20.0 && point.0 < 40.0 && point.1 < 30.0;
+        let outside_measured = Cell::new(0);
+        let distance = |a: &(f64, f64), b: &(f64, f64)| {
+            if !in_box(b) {
+                outside_measured.set(outside_measured.get() + 1);
+            }
+            ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+        };
+        let points: Vec<(f64, f64)> = (0..2500)
+            .map(|i| ((i % 50) as f64 * 1.3, (i / 50) as f64 * 1.1))
+            .collect();
+        let mut tree = VPTree::build_from(points.clone(), distance);
+        tree.extend(vec![(30.02, 10.0), (0.5, 0.5)]);
+        for needle in [(30.0, 10.0), (0.0, 0.0), (60.0, 50.0)].iter() {
+            outside_measured.set(0);
+            let found = tree.find_k_nearest_in_region(needle, 10, in_box);
+            assert!(outside_measured.get() <= tree.radii.len());
+            let expected = tree.find_k_nearest_neighbors_matching(needle, 10, in_box);
+            assert_eq!(found, expected);
+            assert_eq!(found.len(), 10);
+            assert!(found.iter().all(|(_, point)| in_box(point)));
+        }
+        assert_eq!(
+            tree.find_k_nearest_in_region(&(30.0, 10.0), 1, in_box),
+            vec![(distance(&(30.0, 10.0), &(30.02, 10.0)), (30.02, 10.0))]
+        );
+        assert!(tree
+            .find_k_nearest_in_region(&(30.0, 10.0), 0, in_box)
+            .is_empty());
+    }
+    #[test]
+    fn inserting_into_tiny_trees() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        for len in 0..3 {
+            let mut tree = VPTree::build_from(0..len, distance);
+            assert_eq!(tree.depth(), 0);
+            let mut items: Vec<i32> = (0..len).collect();
+            for item in (10..40).step_by(3) {
+                tree.insert(item);
+                items.push(item);
+                assert_eq!(tree.validate(), Ok(()));
+                for needle in [-5, 1, 17, 50].iter() {
+                    let expected: Vec<i32> =
+                        crate::util::brute_force_k_nearest(&items, needle, 3, distance)
+                            .into_iter()
+                            .map(|(distance, _)| distance)
+                            .collect();
+                    let found: Vec<i32> = tree
+                        .find_k_nearest_neighbors(needle, 3)
+                        .into_iter()
+                        .map(|(distance, _)| distance)
+                        .collect();
+                    assert_eq!(found, expected);
+                }
+                assert_eq!(tree.len(), items.len());
+            }
+        }
+    }
+    #[test]
+    fn approximate_count() {
+        use std::cell::Cell;
+        let measured = Cell::new(0);
+        let distance = |a: &(i32, i32), b: &(i32, i32)| {
+            measured.set(measured.get() + 1);
+            (a.0 - b.0).abs() + (a.1 - b.1).abs()
+        };
+        for &len in [0, 1, 2, 7, 100, 1000].iter() {
+            let points = scattered_points(len as usize).into_iter();
+            let mut tree = VPTree::build_from(points.map(|(x, y)| (x as i32, y as i32)), distance);
+            assert_eq!(tree.subtree_len(0), len as usize);
+            tree.insert((500, 500));
+            for needle in [(50, 50), (0, 0), (-30, 120)].iter() {
+                for &threshold in [0, 10, 40, 100, 1000].iter() {
+                    let expected = tree.count_within_radius(needle, threshold);
+                    measured.set(0);
+                    assert_eq!(tree.count_within_radius_approx(needle, threshold), expected);
+                    if len == 1000 && threshold == 1000 {
+                        assert!(measured.get() < 100);
+                    }
+                }
+            }
+        }
+    }
+    #[test]
+    fn nearest_neighbor_within() {
+        let mut tree =
+            VPTree::build_from((0..100).map(|i| i * 10), |a: &i32, b: &i32| (a - b).abs());
+        tree.insert(1003);
+        assert_eq!(tree.find_nearest_neighbor_within(&503, 10), Some((3, 500)));
+        assert_eq!(tree.find_nearest_neighbor_within(&503, 3), None);
+        assert_eq!(tree.find_nearest_neighbor_within(&503, 4), Some((3, 500)));
+        assert_eq!(tree.find_nearest_neighbor_within(&1000, 4), Some((3, 1003)));
+        assert_eq!(tree.find_nearest_neighbor_within(&-50, 0), None);
+        for needle in (-20..1020).step_by(7) {
+            assert_eq!(
+                tree.find_nearest_neighbor_within(&needle, i32::MAX),
+                tree.find_nearest_neighbor(&needle)
+            );
+        }
+    }
+    #[test]
+    fn identical_points() {
+        let distance = euclidean;
+        for &len in [1, 2, 3, 50, 1000].iter() {
+            let mut tree = VPTree::build_from(vec![(1.0, 2.0); len], distance);
+            assert_eq!(tree.validate(), Ok(()));
+            assert_eq!(tree.len(), len);
+            assert_eq!(
+                tree.find_nearest_neighbor(&(1.0, 2.0)),
+                Some((0.0, (1.0, 2.0)))
+            );
+            assert_eq!(
+                tree.find_nearest_neighbor(&(4.0, 6.0)),
+                Some((5.0, (1.0, 2.0)))
+            );
+            assert_eq!(
+                tree.find_k_nearest_neighbors(&(1.0, 2.0), 10),
+                vec![(0.0, (1.0, 2.0)); len.min(10)]
+            );
+            assert_eq!(
+                tree.find_neighbors_within_radius(&(1.0, 2.0), 0.0).len(),
+                len
+            );
+            assert_eq!(
+                tree.find_neighbors_within_radius(&(4.0, 6.0), 5.0).len(),
+                len
+            );
+            assert!(tree
+                .find_neighbors_within_radius(&(4.0, 6.0), 4.9)
+                .is_empty());
+            assert_eq!(tree.count_within_radius(&(1.0, 2.0), 0.0), len);
+        }
+    }
+    #[test]
+    fn querying_an_unbuilt_tree() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let unbuilt = || {
+            let mut tree = VPTree::new(distance);
+            tree.extend((0..300).map(|i| i * 3));
+            assert!(!tree.is_updated);
+            tree
+        };
+        let mut tree = unbuilt();
+        assert_eq!(tree.find_nearest_neighbor(&301), Some((1, 300)));
+        assert!(tree.is_updated);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(
+            unbuilt().find_k_nearest_neighbors(&301, 2),
+            vec![(1, 300), (2, 303)]
+        );
+        assert_eq!(
+            unbuilt().find_neighbors_within_radius(&301, 2),
+            vec![(1, 300), (2, 303)]
+        );
+        assert_eq!(unbuilt().count_within_radius(&301, 30), 20);
+        assert_eq!(
+            unbuilt().nearest_neighbors_iter(&301).next(),
+            Some((1, 300))
+        );
+    }
+    #[test]
+    fn neighbors_within_radius_into() {
+        let mut tree = VPTree::build_from(
+            (0..400).map(|i| [(i % 20) as f64, (i / 20) as f64]),
+            crate::metrics::euclidean,
+        );
+        let mut out = vec![(0.0, [0.0, 0.0]); 1000];
+        for needle in [[3.5, 7.2], [0.0, 0.0], [30.0, 30.0]].iter() {
+            tree.find_neighbors_within_radius_into(needle, 2.5, true, &mut out);
+            let expected = tree.find_neighbors_within_radius_matching(needle, 2.5, |_| true);
+            assert_eq!(out, expected);
+            tree.find_neighbors_within_radius_into(needle, 2.5, false, &mut out);
+            out.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let mut expected = expected;
+            expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert_eq!(out, expected);
+        }
+    }
+    #[test]
+    fn distance_calculator_access() {
+        let tree = VPTree::build_from(0..10, |a: &i32, b: &i32| (a - b).abs() * 2);
+        assert_eq!(tree.distance(&3, &7), 8);
+        assert_eq!((tree.metric())(&7, &3), 8);
+    }
+    #[test]
+    fn nearest_n() {
+        let mut tree = VPTree::build_from(
+            (0..400).map(|i| [(i % 20) as f64 * 0.7, (i / 20) as f64 * 1.3]),
+            crate::metrics::euclidean,
+        );
+        tree.insert([3.0, 3.0]);
+        assert!(tree.depth() > 0);
+        for needle in [[3.1, 2.9], [0.0, 0.0], [-5.0, 40.0]].iter() {
+            let expected = tree.find_k_nearest_neighbors(needle, 5);
+            let found: [Option<(f64, [f64; 2])>; 5] = tree.find_nearest_n(needle);
+            assert_eq!(
+                found.iter().flatten().cloned().collect::<Vec<_>>(),
+                expected
+            );
+            assert_eq!(
+                tree.find_nearest_n::<1>(needle)[0],
+                tree.find_nearest_neighbor(needle)
+            );
+        }
+        assert_eq!(tree.find_nearest_n::<0>(&[0.0, 0.0]), []);
+
+        let mut tree = VPTree::build_from(vec![3, 1], |a: &i32, b: &i32| (a - b).abs());
+        assert_eq!(tree.find_nearest_n(&0), [Some((1, 1)), Some((3, 3)), None]);
+
+        let mut tree = VPTree::new(|a: &i32, b: &i32| (a - b).abs()).with_leaf_size(3);
+        tree.extend((0..20000).map(|i| i * 7919 % 20011));
+        tree.build();
+        assert!(tree.depth() >= 12);
+        for needle in [-7, 5000, 10007, 30000].iter() {
+            let expected = tree.find_k_nearest_neighbors(needle, 4);
+            let found: [Option<(i32, i32)>; 4] = tree.find_nearest_n(needle);
+            assert_eq!(
+                found.iter().flatten().cloned().collect::<Vec<_>>(),
+                expected
+            );
+        }
+    }
+    #[test]
+    #[cfg(feature = "ndarray")]
+    fn from_rows() {
+        let rows = ndarray::Array2::from_shape_fn((100, 3), |(row, column)| {
+            (row * (column + 1) % 17) as f32
+        });
+        let mut tree = VPTree::from_rows(rows.view(), crate::metrics::manhattan);
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.validate(), Ok(()));
+        let needle = rows.row(42).to_vec();
+        assert_eq!(
+            tree.find_nearest_neighbor(&needle),
+            Some((0.0, needle.clone()))
+        );
+        /* Columns of a transposed view aren't contiguous in memory */
+        let tree = VPTree::from_rows(rows.t(), crate::metrics::manhattan);
+        assert_eq!(tree.len(), 3);
+    }
+    #[test]
+    fn get_by_insertion_order() {
+        let mut tree =
+            VPTree::build_from((0..100).map(|i| i * 3), |a: &i32, b: &i32| (a - b).abs());
+        tree.insert(-1);
+        for index in 0..100 {
+            assert_eq!(tree.get(index), Some(&(index as i32 * 3)));
+        }
+        assert_eq!(tree.get(100), Some(&-1));
+        assert_eq!(tree.get(101), None);
+        let (_, index) = tree.find_nearest_neighbor_indexed(&31).unwrap();
+        assert_eq!(tree.get(index), Some(&30));
+        tree.retain(|item| item % 2 == 0);
+        assert_eq!(tree.get(3), None);
+        assert_eq!(tree.get(4), Some(&12));
+    }
+    #[test]
+    fn knn_graph() {
+        let points: Vec<[f64; 2]> = (0..200)
+            .map(|i| [((i * 37) % 101) as f64, ((i * 53) % 97) as f64])
+            .collect();
+        let mut tree = VPTree::build_from(points.clone(), crate::metrics::euclidean);
+        tree.insert(points[5]);
+        let mut items = points.clone();
+        items.push(points[5]);
+        let graph = tree.knn_graph(4);
+        assert_eq!(graph.len(), 201);
+        for (id, neighbors) in graph.iter().enumerate() {
+            let others: Vec<[f64; 2]> = (0..items.len())
+                .filter(|&other| other != id)
+                .map(|other| items[other])
+                .collect();
+            let expected: Vec<f64> = crate::util::brute_force_k_nearest(
+                &others,
+                &items[id],
+                4,
+                crate::metrics::euclidean,
+            )
+            .into_iter()
+            .map(|(distance, _)| distance)
+            .collect();
+            let distances: Vec<f64> = neighbors.iter().map(|&(distance, _)| distance).collect();
+            assert_eq!(distances, expected);
+            for &(distance, other) in neighbors {
+                assert_ne!(other, id);
+                assert_eq!(
+                    crate::metrics::euclidean(&items[id], &items[other]),
+                    distance
+                );
+            }
+        }
+        assert_eq!(graph[5][0], (0.0, 200));
+        assert_eq!(graph[200][0], (0.0, 5));
+        tree.retain(|point| point[0] >= 10.0);
+        let graph = tree.knn_graph(1);
+        assert_eq!(graph.len(), 201);
+        for (id, neighbors) in graph.iter().enumerate() {
+            assert_eq!(neighbors.is_empty(), items[id][0] < 10.0);
+        }
+        assert!(tree.knn_graph(0).iter().all(Vec::is_empty));
+    }
+    #[test]
+    fn knn_graph_of_zero_sized_items() {
+        /* All the items share one address, so only their positions tell them apart */
+        let mut tree = VPTree::build_from(vec![(); 20], |_: &(), _: &()| 0u32);
+        let graph = tree.knn_graph(3);
+        assert_eq!(graph.len(), 20);
+        for (id, neighbors) in graph.iter().enumerate() {
+            let expected: Vec<(u32, usize)> = (0..20)
+                .filter(|&other| other != id)
+                .take(3)
+                .map(|other| (0, other))
+                .collect();
+            assert_eq!(*neighbors, expected);
+        }
+    }
+    #[cfg(feature = "half")]
+    #[test]
+    fn half_precision_distances() {
+        use half::f16;
+        let distance = |a: &(f32, f32), b: &(f32, f32)| {
+            f16::from_f32(((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt())
+        };
+        /* Coordinates up to a few hundred, where f16 can only tell apart distances about
+        a quarter apart, so there are plenty of ties and rounding at node boundaries */
+        let points: Vec<(f32, f32)> = (0..1000)
+            .map(|i| {
+                (
+                    ((i * 37) % 401) as f32 * 0.75,
+                    ((i * 53) % 397) as f32 * 0.75,
+                )
+            })
+            .collect();
+        let mut tree = VPTree::build_from(points.clone(), distance);
+        assert_eq!(tree.validate(), Ok(()));
+        for i in 0..50 {
+            let needle = (i as f32 * 6.1, 300.0 - i as f32 * 5.3);
+            let expected: Vec<f16> =
+                crate::util::brute_force_k_nearest(&points, &needle, points.len(), distance)
+                    .into_iter()
+                    .map(|(distance, _)| distance)
+                    .collect();
+            let nearest: Vec<f16> = tree
+                .find_k_nearest_neighbors(&needle, 20)
+                .into_iter()
+                .map(|(distance, _)| distance)
+                .collect();
+            assert_eq!(nearest, expected[..20].to_vec());
+            assert_eq!(
+                tree.find_nearest_neighbor(&needle)
+                    .map(|(distance, _)| distance),
+                Some(expected[0])
+            );
+            let threshold = f16::from_f32(40.0);
+            assert_eq!(
+                tree.count_within_radius(&needle, threshold),
+                expected.iter().filter(|&&d| d <= threshold).count()
+            );
+        }
+        /* Past f16::MAX, distances round to infinity, which no nearest neighbor query returns */
+        let far = (100000.0, 0.0);
+        let mut tree = VPTree::build_from(vec![(0.0, 0.0), (1.0, 0.0), far], distance);
+        assert_eq!(distance(&(0.0, 0.0), &far), f16::INFINITY);
+        assert_eq!(tree.find_k_nearest_neighbors(&(0.0, 0.0), 3).len(), 2);
+        assert_eq!(
+            tree.find_neighbors_within_radius(&(0.0, 0.0), f16::INFINITY)
+                .len(),
+            3
+        );
+    }
+    #[test]
+    fn prune_to_capacity() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        let mut tree = VPTree::build_from((0..100).map(|i| i * 2), distance);
+        tree.insert(51);
+        tree.prune_to_capacity(&200, 200);
+        assert_eq!(tree.len(), 101);
+        tree.prune_to_capacity(&50, 10);
+        assert_eq!(tree.validate(), Ok(()));
+        let mut items = tree.clone().into_items();
+        items.sort_unstable();
+        assert_eq!(items, vec![42, 44, 46, 48, 50, 51, 52, 54, 56, 58]);
+        /* The numbers of the items that stay don't change */
+        assert_eq!(tree.get(100), Some(&51));
+        assert_eq!(tree.get(20), None);
+        assert_eq!(tree.get(21), Some(&42));
+        assert_eq!(tree.find_nearest_neighbor(&0), Some((42, 42)));
+        tree.prune_to_capacity(&50, 0);
+        assert!(tree.is_empty());
+    }
+    #[test]
+    fn neighbors_within_radius_exclusive() {
+        let distance = |a: &(i32, i32), b: &(i32, i32)| (a.0 - b.0).abs() + (a.1 - b.1).abs();
+        let points: Vec<(i32, i32)> = (0..441).map(|i| (i % 21, i / 21)).collect();
+        let mut tree = VPTree::build_from(points.clone(), distance);
+        for &needle in [(10, 10), (0, 0), (3, 17), (25, -4)].iter() {
+            for threshold in 0..12 {
+                let mut expected: Vec<i32> = points
+                    .iter()
+                    .map(|point| distance(&needle, point))
+                    .filter(|&d| d < threshold)
+                    .collect();
+                expected.sort_unstable();
+                let within = tree.find_neighbors_within_radius_exclusive(&needle, threshold);
+                let distances: Vec<i32> = within.iter().map(|&(d, _)| d).collect();
+                assert_eq!(distances, expected);
+                let on_boundary = points
+                    .iter()
+                    .filter(|point| distance(&needle, point) == threshold)
+                    .count();
+                assert_eq!(
+                    tree.find_neighbors_within_radius(&needle, threshold).len(),
+                    within.len() + on_boundary
+                );
+            }
+        }
+        /* Two balls that touch at (10, 10) share no item */
+        let left = tree.find_neighbors_within_radius_exclusive(&(7, 10), 3);
+        let right = tree.find_neighbors_within_radius_exclusive(&(13, 10), 3);
+        assert!(left.iter().all(|item| !right.contains(item)));
+        assert!(tree
+            .find_neighbors_within_radius_exclusive(&(10, 10), 0)
+            .is_empty());
+    }
+    #[test]
+    fn leaf_sizes() {
+        let distance = |a: &i32, b: &i32| (a - b).abs();
+        for &leaf_size in [3, 4, 5, 17, 200].iter() {
+            for len in (0..300).step_by(7) {
+                let mut tree = VPTree::new(distance).with_leaf_size(leaf_size);
+                tree.extend((0..len).map(|i| (i * 37) % 301));
+                tree.build();
+                assert_eq!(tree.validate(), Ok(()));
+                let (fewest, most, _) = tree.leaf_occupancy();
+                assert!(most <= leaf_size);
+                assert!(tree.leaf_count() == 1 || fewest + 1 >= leaf_size / 2);
+                for needle in [-3, 40, 150, 299].iter() {
+                    let distances: Vec<i32> = tree
+                        .find_k_nearest_neighbors(needle, 6)
+                        .into_iter()
+                        .map(|(distance, _)| distance)
+                        .collect();
+                    let mut expected: Vec<i32> = (0..len)
+                        .map(|i| distance(needle, &((i * 37) % 301)))
+                        .collect();
+                    expected.sort_unstable();
+                    expected.truncate(6);
+                    assert_eq!(distances, expected);
+                }
+            }
+        }
+
+        let mut tree = VPTree::build_from((0..1000).map(|i| i * 2), distance);
+        tree.insert(7);
+        tree.rebuild_with_leaf_size(4);
+        assert_eq!(tree.validate(), Ok(()));
+        assert!(tree.leaf_occupancy().1 <= 4);
+        assert_eq!(tree.get(1000), Some(&7));
+        assert_eq!(tree.find_nearest_neighbor_indexed(&7), Some((0, 1000)));
+        tree.rebuild_with_leaf_size(100);
+        assert_eq!(tree.validate(), Ok(()));
+        assert!(tree.leaf_occupancy().1 <= 100);
+        assert_eq!(tree.len(), 1001);
+        let tree = tree.map_metric(|a: &i32, b: &i32| (a - b).abs() * 2);
+        assert!(tree.leaf_occupancy().1 <= 100);
+    }
+    #[test]
+    #[should_panic(expected = "at least 3 items")]
+    fn tiny_leaves() {
+        VPTree::new(|a: &i32, b: &i32| (a - b).abs()).with_leaf_size(2);
+    }
+    #[test]
+    fn cached_nearest_neighbor() {
+        use std::cell::Cell;
+        let calls = Cell::new(0);
+        let distance = |a: &i32, b: &i32| {
+            calls.set(calls.get() + 1);
+            (a - b).abs()
+        };
+        let mut cached = CachedVPTree::new(VPTree::build_from((0..100).map(|i| i * 3), distance));
+        assert_eq!(cached.find_nearest_neighbor(&31), Some((1, 30)));
+        let searched = calls.get();
+        assert_eq!(cached.find_nearest_neighbor(&31), Some((1, 30)));
+        assert_eq!(calls.get(), searched);
+        assert_eq!(cached.find_nearest_neighbor(&50), Some((1, 51)));
+        assert!(calls.get() > searched);
+        cached.tree_mut().insert(31);
+        assert_eq!(cached.find_nearest_neighbor(&31), Some((0, 31)));
+        let searched = calls.get();
+        assert_eq!(cached.find_nearest_neighbor(&31), Some((0, 31)));
+        assert_eq!(calls.get(), searched);
+        assert_eq!(cached.tree().len(), 101);
+        assert_eq!(cached.into_inner().len(), 101);
+    }
+    #[test]
+    fn results_after_inserts() {
+        /* Pseudorandom numbers, from a linear congruential generator */
+        let mut state = 7u64;
+        let mut random = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 40) as i64
+        };
+        let distance = |a: &i64, b: &i64| (a - b).abs();
+        let mut tree = VPTree::new(distance);
+        let mut items = Vec::new();
+        for round in 0..60 {
+            /* Single inserts pile up as pending items, extend rebuilds the tree when needed */
+            if round % 3 == 0 {
+                let batch: Vec<i64> = (0..round).map(|_| random()).collect();
+                items.extend(batch.iter().cloned());
+                tree.extend(batch);
+            } else {
+                let item = random();
+                items.push(item);
+                tree.insert(item);
+            }
+            for _ in 0..5 {
+                let needle = random();
+                let k = (random() % 12) as usize;
+                let nearest = tree.find_k_nearest_neighbors(&needle, k);
+                let indexed = tree.find_k_nearest_neighbors_indexed(&needle, k);
+                let mut expected: Vec<i64> =
+                    items.iter().map(|item| distance(&needle, item)).collect();
+                expected.sort_unstable();
+                expected.truncate(k);
+                let distances: Vec<i64> = nearest.iter().map(|&(d, _)| d).collect();
+                assert_eq!(distances, expected);
+                let mut ids = HashSet::new();
+                for (&(d, item), &(indexed_distance, id)) in nearest.iter().zip(indexed.iter()) {
+                    assert_eq!(distance(&needle, &item), d);
+                    assert_eq!(indexed_distance, d);
+                    assert_eq!(tree.get(id), Some(&item));
+                    assert_eq!(items[id], item);
+                    assert!(ids.insert(id));
+                }
+                assert_eq!(
+                    tree.find_nearest_neighbor(&needle).map(|(d, _)| d),
+                    items.iter().map(|item| distance(&needle, item)).min()
+                );
+                let threshold = expected.last().cloned().unwrap_or(0);
+                let within = tree.find_neighbors_within_radius(&needle, threshold);
+                assert_eq!(
+                    within.len(),
+                    items
+                        .iter()
+                        .filter(|item| distance(&needle, item) <= threshold)
+                        .count()
+                );
+                assert!(within
+                    .iter()
+                    .all(|&(d, item)| distance(&needle, &item) == d));
+            }
+        }
+    }
+    #[test]
+    fn k_best_near() {
+        use std::cell::Cell;
+        let scored = Cell::new(0);
+        let mut tree = VPTree::build_from(0..1000, |a: &i32, b: &i32| (a - b).abs());
+        /* Scores favor multiples of 7, then larger numbers */
+        let score = |item: &i32| {
+            scored.set(scored.get() + 1);
+            if item % 7 == 0 {
+                1000.0 + *item as f64
+            } else {
+                *item as f64
+            }
+        };
+        assert_eq!(
+            tree.find_k_best_near(&500, 20, 4, score),
+            vec![(1518.0, 518), (1511.0, 511), (1504.0, 504), (1497.0, 497)]
+        );
+        assert_eq!(scored.get(), 41);
+        assert_eq!(tree.find_k_best_near(&500, 20, 0, score), Vec::new());
+        assert_eq!(tree.find_k_best_near(&500, 2, 10, score).len(), 5);
+        /* Equal scores are sorted by distance, NaN scores are left out */
+        let best = tree.find_k_best_near(
+            &500,
+            3,
+            10,
+            |item: &i32| {
+                if *item == 500 {
+                    f64::NAN
+                } else {
+                    1.0
+                }
+            },
+        );
+        let distances: Vec<i32> = best.iter().map(|&(_, item)| (item - 500).abs()).collect();
+        assert_eq!(distances, vec![1, 1, 2, 2, 3, 3]);
+    }
+    #[test]
+    fn neighbors_within_radius_sorted_by() {
+        /* Points with a priority the distance ignores */
+        let points: Vec<(i32, u32)> = (0..200).map(|i| (i, (i as u32 * 7919) % 31)).collect();
+        let mut tree =
+            VPTree::build_from(points, |a: &(i32, u32), b: &(i32, u32)| (a.0 - b.0).abs());
+        let by_priority = tree.find_neighbors_within_radius_sorted_by(&(100, 0), 10, |a, b| {
+            b.1 .1.cmp(&a.1 .1).then(a.1 .0.cmp(&b.1 .0))
+        });
+        let mut expected = tree.find_neighbors_within_radius(&(100, 0), 10);
+        assert_eq!(expected.len(), 21);
+        expected.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.1 .0.cmp(&b.1 .0)));
+        assert_eq!(by_priority, expected);
+        let farthest_first =
+            tree.find_neighbors_within_radius_sorted_by(&(100, 0), 10, |a, b| b.0.cmp(&a.0));
+        assert_eq!(farthest_first.first().map(|n| n.0), Some(10));
+        assert_eq!(farthest_first.last(), Some(&(0, (100, (100 * 7919) % 31))));
     }
 }